@@ -0,0 +1,34 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use untitled2::vm::Vm;
+
+// Counts register 1 up to 10 000, three instructions per iteration
+const COUNTING_LOOP: [u8; 14] = [
+    1, 0, 0x27, 0x10, // LOAD 10 000 to register 0
+    18, 1, // INC register 1
+    12, 1, 0, // LT register 1 and register 0
+    15, 255, 0, 4, // JEQ 4
+    0, // HLT
+];
+
+fn counting_loop(c: &mut Criterion) {
+    let mut vm = Vm::with_program(COUNTING_LOOP.to_vec());
+    c.bench_function("interpreted", |b| {
+        b.iter(|| {
+            vm.pc = 0;
+            vm.registers = [0; 32];
+            vm.run()
+        })
+    });
+
+    vm.compile().unwrap();
+    c.bench_function("compiled", |b| {
+        b.iter(|| {
+            vm.pc = 0;
+            vm.registers = [0; 32];
+            vm.run_compiled()
+        })
+    });
+}
+
+criterion_group!(benches, counting_loop);
+criterion_main!(benches);
@@ -0,0 +1,133 @@
+//! Decoding of bytecode back into structured instructions
+use super::{Instruction, Token};
+use crate::instruction::{instruction_size, Opcode, OperandType, IMMEDIATE_TARGET};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DecodeError {
+    IllegalOpcode { offset: usize, byte: u8 },
+    Truncated { offset: usize, opcode: Opcode },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::IllegalOpcode { offset, byte } => {
+                write!(f, "Illegal opcode {:#04X} at {}", byte, offset)
+            }
+            DecodeError::Truncated { offset, opcode } => {
+                write!(f, "Truncated {:?} instruction at {}", opcode, offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Outcome of `decode_one`
+#[derive(Debug, PartialEq)]
+pub enum DecodeResult {
+    /// A whole instruction, `width` bytes long
+    Decoded {
+        instruction: Instruction,
+        width: usize,
+    },
+    /// The bytes end before the instruction does, or `offset` is past the end
+    Truncated,
+    Illegal,
+}
+
+/// Decodes the instruction starting at `offset`
+///
+/// Panics if `offset` is past the end of `bytes`, use `decode_one` for untrusted input.
+pub fn decode_instruction(bytes: &[u8], offset: usize) -> Result<Instruction, DecodeError> {
+    let byte = bytes[offset];
+    match decode_one(bytes, offset) {
+        DecodeResult::Decoded { instruction, .. } => Ok(instruction),
+        DecodeResult::Truncated => Err(DecodeError::Truncated {
+            offset,
+            opcode: Opcode::from(byte),
+        }),
+        DecodeResult::Illegal => Err(DecodeError::IllegalOpcode { offset, byte }),
+    }
+}
+
+/// Decodes the instruction starting at `offset` without ever indexing out of bounds
+pub fn decode_one(bytes: &[u8], offset: usize) -> DecodeResult {
+    let Some(&byte) = bytes.get(offset) else {
+        return DecodeResult::Truncated;
+    };
+    let opcode = Opcode::from(byte);
+    if opcode == Opcode::Igl {
+        return DecodeResult::Illegal;
+    }
+    let width = match instruction_size(bytes, offset) {
+        Some(width) if width <= bytes.len() - offset => width,
+        _ => return DecodeResult::Truncated,
+    };
+
+    let mut cursor = offset + 1;
+    let mut operands = vec![];
+    for operand_type in opcode.operands().operands {
+        let operand = &bytes[cursor..cursor + operand_type.size()];
+        operands.push(match operand_type {
+            OperandType::Register => Token::Register { reg: operand[0] },
+            OperandType::Number => Token::Number {
+                num: i16::from_be_bytes([operand[0], operand[1]]) as i32,
+            },
+            // Label names are not part of the bytecode, only the unsigned address they resolved to
+            OperandType::Target if operand[0] == IMMEDIATE_TARGET => Token::Number {
+                num: u16::from_be_bytes([operand[1], operand[2]]) as i32,
+            },
+            OperandType::Target => Token::Register { reg: operand[0] },
+            OperandType::RegisterList => {
+                let count = operand[0] as usize;
+                let regs = bytes[cursor + 1..cursor + 1 + count].to_vec();
+                cursor += count;
+                Token::RegisterList { regs }
+            }
+        });
+        cursor += operand_type.size();
+    }
+
+    DecodeResult::Decoded {
+        instruction: Instruction { opcode, operands },
+        width,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_decode_one() {
+        assert_eq!(decode_one(&[1, 0, 0], 0), DecodeResult::Truncated);
+        assert_eq!(decode_one(&[0], 1), DecodeResult::Truncated);
+        assert_eq!(decode_one(&[0, 255], 1), DecodeResult::Illegal);
+        assert_eq!(
+            decode_one(&[23, 0, 2, 1, 2], 0),
+            DecodeResult::Decoded {
+                instruction: Instruction {
+                    opcode: Opcode::Sumall,
+                    operands: vec![
+                        Token::Register { reg: 0 },
+                        Token::RegisterList { regs: vec![1, 2] }
+                    ]
+                },
+                width: 5
+            }
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_decode_one_never_panics(
+            bytes in prop::collection::vec(any::<u8>(), 0..64),
+            offset in 0usize..80,
+        ) {
+            let _ = decode_one(&bytes, offset);
+        }
+    }
+}
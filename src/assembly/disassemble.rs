@@ -0,0 +1,140 @@
+//! Rendering of bytecode as human readable assembly
+use super::{decode_one, AssembleError, DecodeError, DecodeResult, Program, Token};
+use crate::instruction::{Opcode, OperandType};
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Radix {
+    #[default]
+    Decimal,
+    Hex,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct DisassemblyOptions {
+    /// How immediates and addresses are displayed
+    pub radix: Radix,
+}
+
+// Immediates already carry their sign from decoding, so only the radix is applied here
+fn format_number(num: i32, radix: Radix) -> String {
+    match radix {
+        Radix::Decimal => num.to_string(),
+        Radix::Hex if num < 0 => format!("-{:#X}", num.unsigned_abs()),
+        Radix::Hex => format!("{:#X}", num),
+    }
+}
+
+/// Disassembles `bytes` into one `offset: MNEMONIC operands` line per instruction
+pub fn disassemble(bytes: &[u8], options: &DisassemblyOptions) -> Result<String, DecodeError> {
+    disassemble_with_symbols(bytes, options, &HashMap::new())
+}
+
+/// Like `disassemble`, but shows jumps to an address in `symbols` as a jump to that label
+pub fn disassemble_with_symbols(
+    bytes: &[u8],
+    options: &DisassemblyOptions,
+    symbols: &HashMap<String, usize>,
+) -> Result<String, DecodeError> {
+    // Of several labels on one address the alphabetically first is shown, for stable output
+    let mut labels: HashMap<usize, &str> = HashMap::new();
+    for (name, &address) in symbols {
+        let label = labels.entry(address).or_insert(name);
+        if name.as_str() < *label {
+            *label = name;
+        }
+    }
+
+    let mut output = String::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let (instruction, width) = match decode_one(bytes, offset) {
+            DecodeResult::Decoded { instruction, width } => (instruction, width),
+            DecodeResult::Truncated => {
+                let opcode = Opcode::from(bytes[offset]);
+                return Err(DecodeError::Truncated { offset, opcode });
+            }
+            DecodeResult::Illegal => {
+                let byte = bytes[offset];
+                return Err(DecodeError::IllegalOpcode { offset, byte });
+            }
+        };
+        output.push_str(&format!("{:04}: {}", offset, instruction.opcode.mnemonic()));
+        let operand_types = instruction.opcode.operands().operands;
+        for (operand, operand_type) in instruction.operands.iter().zip(operand_types) {
+            let label = match (operand, operand_type) {
+                (Token::Number { num }, OperandType::Target) => labels.get(&(*num as usize)),
+                _ => None,
+            };
+            let operand = match (operand, label) {
+                (_, Some(label)) => label.to_string(),
+                (Token::Number { num }, None) => format_number(*num, options.radix),
+                (operand, None) => operand.to_string(),
+            };
+            output.push(' ');
+            output.push_str(&operand);
+        }
+        output.push('\n');
+        offset += width;
+    }
+    Ok(output)
+}
+
+impl Program {
+    /// Disassembles the assembled program, showing jumps to labels by their name
+    ///
+    /// Fails only if the program does not assemble, see `Program::to_bytes`.
+    pub fn disassemble(&self, options: &DisassemblyOptions) -> Result<String, AssembleError> {
+        let bytes = self.to_bytes()?;
+        Ok(disassemble_with_symbols(&bytes, options, &self.symbols)
+            .expect("assembled bytes always decode"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble_signed_immediate() {
+        let bytes = [1, 0, 255, 255];
+
+        assert_eq!(
+            disassemble(&bytes, &DisassemblyOptions::default()),
+            Ok("0000: LOAD $0 -1\n".to_string())
+        );
+        assert_eq!(
+            disassemble(&bytes, &DisassemblyOptions { radix: Radix::Hex }),
+            Ok("0000: LOAD $0 -0x1\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_disassemble_unsigned_address() {
+        let bytes = [6, 255, 255, 240];
+
+        assert_eq!(
+            disassemble(&bytes, &DisassemblyOptions::default()),
+            Ok("0000: JMP 65520\n".to_string())
+        );
+        assert_eq!(
+            disassemble(&bytes, &DisassemblyOptions { radix: Radix::Hex }),
+            Ok("0000: JMP 0xFFF0\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_disassemble_labeled_loop() {
+        let program = Program::from_str("LOAD $0 3\nloop:\nDEC $0\nJMP loop\nJMP 100\n").unwrap();
+
+        assert_eq!(
+            program.disassemble(&DisassemblyOptions::default()).unwrap(),
+            "0000: LOAD $0 3\n0004: DEC $0\n0006: JMP loop\n0010: JMP 100\n"
+        );
+        // Without the symbol table only the address is known
+        assert_eq!(
+            disassemble(&program.to_bytes().unwrap(), &DisassemblyOptions::default()),
+            Ok("0000: LOAD $0 3\n0004: DEC $0\n0006: JMP 4\n0010: JMP 100\n".to_string())
+        );
+    }
+}
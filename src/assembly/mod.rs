@@ -4,55 +4,333 @@
 //! LOAD $0 1E
 //! LOAD $1 14
 //! ADD $0 $1 $3
-use crate::instruction::{Opcode, OperandType};
+//! loop:
+//! JMP loop
+use crate::instruction::{Encoding, Opcode, OperandType, IMMEDIATE_TARGET};
+use crate::vm::{Vm, REGISTER_COUNT};
 use nom::branch::alt;
-use nom::bytes::complete::{tag, take_until};
-use nom::character::complete::{alphanumeric1, multispace0, newline, space1};
-use nom::combinator::{map, map_res, value};
-use nom::multi::many1;
-use nom::sequence::{preceded, terminated};
+use nom::bytes::complete::{tag, tag_no_case, take_until, take_while1};
+use nom::character::complete::{
+    alpha1, alphanumeric1, char, digit1, multispace0, newline, one_of, satisfy, space0, space1,
+};
+use nom::combinator::{
+    all_consuming, map, map_opt, map_res, not, opt, recognize, rest, value, verify,
+};
+use nom::multi::{many0, many0_count, separated_list1};
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+mod decode;
+mod disassemble;
+mod optimize;
+mod parse_error;
+
+pub use decode::{decode_instruction, decode_one, DecodeError, DecodeResult};
+pub use disassemble::{disassemble, disassemble_with_symbols, DisassemblyOptions, Radix};
+pub use parse_error::{ParseError, ParseErrorKind};
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
-    Op { code: Opcode },
-    Register { reg: u8 },
-    Number { num: i32 },
+    Op {
+        code: Opcode,
+    },
+    Register {
+        reg: u8,
+    },
+    Number {
+        num: i32,
+    },
+    Label(String),
+    RegisterList {
+        regs: Vec<u8>,
+    },
+    /// Labels and numbers added up once labels are resolved, each with a coefficient of 1 or -1
+    Expression(Vec<(i32, Token)>),
+}
+
+impl Token {
+    // Value of a number, label or expression once labels resolve through `symbols`
+    //
+    // Sums too large for an `i32` saturate, they are out of range for any operand anyway.
+    fn evaluate(&self, symbols: &HashMap<String, usize>) -> Result<i32, AssembleError> {
+        let sum = match self {
+            Token::Number { num } => *num as i64,
+            Token::Label(name) => *symbols
+                .get(name)
+                .ok_or_else(|| AssembleError::UndefinedLabel(name.clone()))?
+                as i64,
+            Token::Expression(terms) => terms
+                .iter()
+                .map(|(coefficient, term)| Ok(*coefficient as i64 * term.evaluate(symbols)? as i64))
+                .sum::<Result<i64, AssembleError>>()?,
+            _ => unreachable!("only numbers, labels and expressions have a value"),
+        };
+        Ok(sum.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+    }
+
+    // Replaces references to `constants` by their value
+    fn substitute(&mut self, constants: &HashMap<String, i32>) {
+        match self {
+            Token::Label(name) => {
+                if let Some(&num) = constants.get(name) {
+                    *self = Token::Number { num };
+                }
+            }
+            Token::Expression(terms) => {
+                for (_, term) in terms {
+                    term.substitute(constants);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Labels the token refers to, directly or inside an expression
+    fn labels(&self) -> Vec<&str> {
+        match self {
+            Token::Label(name) => vec![name],
+            Token::Expression(terms) => terms.iter().flat_map(|(_, term)| term.labels()).collect(),
+            _ => vec![],
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Op { code } => write!(f, "{}", code.mnemonic()),
+            Token::Register { reg } => write!(f, "${}", reg),
+            Token::Number { num } => write!(f, "{}", num),
+            Token::Label(name) => write!(f, "{}", name),
+            Token::RegisterList { regs } => {
+                let regs: Vec<String> = regs.iter().map(|reg| format!("${}", reg)).collect();
+                write!(f, "{}", regs.join(" "))
+            }
+            Token::Expression(terms) => {
+                for (i, (coefficient, term)) in terms.iter().enumerate() {
+                    match (i, coefficient) {
+                        (0, 1) => {}
+                        (_, 1) => write!(f, "+")?,
+                        _ => write!(f, "-")?,
+                    }
+                    write!(f, "{}", term)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A single line of assembly: a label definition, an instruction or a directive
+#[derive(Debug, PartialEq)]
+pub enum Statement {
+    Label(String),
+    Instruction(Instruction),
+    /// `.include "path"`, replaced by the statements of that file when assembling
+    Include(String),
+    /// `.data` or `.code`, selecting where the following lines go
+    Section(Section),
+    /// `.string "text"` or `.bytes 1 2 3` in the data section
+    Data(Vec<u8>),
+    /// `.const NAME value`, a name for a number usable wherever an immediate is
+    Const(String, i32),
+}
+
+/// Part of the program a statement belongs to
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Section {
+    /// Instructions, the default
+    Code,
+    /// Initial heap contents, with labels resolving to heap offsets
+    Data,
 }
 
+// Alternative names common in other assemblers, accepted only when parsing
+const OPCODE_ALIASES: [(&str, Opcode); 4] = [
+    ("GE", Opcode::Gtq),
+    ("LE", Opcode::Ltq),
+    ("NE", Opcode::Neq),
+    ("LOADB", Opcode::Loadm),
+];
+
+// Mnemonics are matched as whole words, so `LTQ` is never read as `LT` followed by `Q`
 pub fn parse_opcode(input: &str) -> nom::IResult<&str, Opcode> {
-    alt((
-        value(Opcode::Hlt, tag("HLT")),
-        value(Opcode::Load, tag("LOAD")),
-        value(Opcode::Add, tag("ADD")),
-        value(Opcode::Sub, tag("SUB")),
-        value(Opcode::Mul, tag("MUL")),
-        value(Opcode::Div, tag("DIV")),
-        value(Opcode::Jmp, tag("JMP")),
-        value(Opcode::Jmpf, tag("JMPF")),
-        value(Opcode::Jmpb, tag("JMPB")),
-        value(Opcode::Eq, tag("EQ")),
-        value(Opcode::Neq, tag("NEQ")),
-        value(Opcode::Gt, tag("GT")),
-        value(Opcode::Lt, tag("LT")),
-        value(Opcode::Gtq, tag("GTQ")),
-        value(Opcode::Ltq, tag("LTQ")),
-        value(Opcode::Jeq, tag("JEQ")),
-    ))(input)
+    map_opt(parse_identifier, |word| {
+        let mnemonics = Opcode::all()
+            .iter()
+            .map(|opcode| (opcode.mnemonic(), *opcode));
+        mnemonics
+            .chain(OPCODE_ALIASES)
+            .find(|(mnemonic, _)| *mnemonic == word)
+            .map(|(_, opcode)| opcode)
+    })(input)
+}
+
+// A register index that exists, `$0` to `$31`
+fn parse_register_index(input: &str) -> nom::IResult<&str, u8> {
+    preceded(
+        tag("$"),
+        verify(nom::character::complete::u8, |&reg| {
+            (reg as usize) < REGISTER_COUNT
+        }),
+    )(input)
 }
 
 pub fn parse_register(input: &str) -> nom::IResult<&str, Token> {
-    use nom::character::complete::u8;
-    let (rem, reg) = preceded(tag("$"), u8)(input)?;
+    let (rem, reg) = parse_register_index(input)?;
 
     Ok((rem, Token::Register { reg }))
 }
 
+// Digits may be grouped with single underscores, e.g. `1_000_000`
+fn parse_digits(input: &str) -> nom::IResult<&str, &str> {
+    recognize(pair(digit1, many0_count(preceded(char('_'), digit1))))(input)
+}
+
+// Like `parse_digits`, in any radix up to 16
+fn parse_radix_digits(radix: u32) -> impl FnMut(&str) -> nom::IResult<&str, &str> {
+    move |input| {
+        let digits = || take_while1(|c: char| c.is_digit(radix));
+        recognize(pair(digits(), many0_count(preceded(char('_'), digits()))))(input)
+    }
+}
+
+// Decimal, `0x` hexadecimal or `0b` binary
+fn parse_magnitude(input: &str) -> nom::IResult<&str, i32> {
+    let radix = |prefix, radix| {
+        map_res(
+            preceded(tag_no_case(prefix), parse_radix_digits(radix)),
+            move |digits: &str| i32::from_str_radix(&digits.replace('_', ""), radix),
+        )
+    };
+    alt((
+        radix("0x", 16),
+        radix("0b", 2),
+        map_res(parse_digits, |digits: &str| {
+            digits.replace('_', "").parse::<i32>()
+        }),
+    ))(input)
+}
+
 pub fn parse_number(input: &str) -> nom::IResult<&str, Token> {
-    let (rem, num) = map_res(alphanumeric1, |digit_str: &str| digit_str.parse::<i32>())(input)?;
+    let (rem, (sign, magnitude)) = terminated(
+        pair(opt(one_of("+-")), parse_magnitude),
+        not(satisfy(|c| c.is_alphanumeric() || c == '_')),
+    )(input)?;
+    let num = if sign == Some('-') {
+        -magnitude
+    } else {
+        magnitude
+    };
 
     Ok((rem, Token::Number { num }))
 }
 
+// Label names follow the usual identifier rules: a letter or `_`, then letters, digits or `_`
+fn parse_identifier(input: &str) -> nom::IResult<&str, &str> {
+    recognize(pair(
+        alt((alpha1, tag("_"))),
+        many0_count(alt((alphanumeric1, tag("_")))),
+    ))(input)
+}
+
+pub fn parse_label(input: &str) -> nom::IResult<&str, Token> {
+    let (rem, name) = parse_identifier(input)?;
+
+    Ok((rem, Token::Label(name.to_string())))
+}
+
+// One or more registers separated by spaces, e.g. `$1 $2 $3`
+//
+// The count is encoded in a single byte, so a list holds at most 255 registers.
+pub fn parse_register_list(input: &str) -> nom::IResult<&str, Token> {
+    let (rem, regs) = verify(
+        separated_list1(space1, parse_register_index),
+        |regs: &Vec<u8>| regs.len() <= u8::MAX as usize,
+    )(input)?;
+
+    Ok((rem, Token::RegisterList { regs }))
+}
+
+// Terms of `a+b-c`, spaces around the operators are only allowed inside parentheses
+fn parse_sum(spaced: bool) -> impl FnMut(&str) -> nom::IResult<&str, Vec<(i32, Token)>> {
+    move |input| {
+        let space = |input| {
+            if spaced {
+                space0(input)
+            } else {
+                Ok((input, ""))
+            }
+        };
+        let (mut rem, mut terms) = parse_term(input)?;
+        while let Ok((rest, (operator, term))) =
+            pair(delimited(space, one_of("+-"), space), parse_term)(rem)
+        {
+            let sign = if operator == '-' { -1 } else { 1 };
+            terms.extend(
+                term.into_iter()
+                    .map(|(coefficient, token)| (sign * coefficient, token)),
+            );
+            rem = rest;
+        }
+        Ok((rem, terms))
+    }
+}
+
+// A number, a label or a parenthesized sum, flattened into signed terms
+fn parse_term(input: &str) -> nom::IResult<&str, Vec<(i32, Token)>> {
+    alt((
+        delimited(
+            pair(char('('), space0),
+            parse_sum(true),
+            pair(space0, char(')')),
+        ),
+        map(alt((parse_number, parse_label)), |token| vec![(1, token)]),
+    ))(input)
+}
+
+/// An immediate operand: a number, a label or a `+`/`-` expression of them evaluated left
+/// to right, e.g. `(2+3)` or `BASE+4`
+///
+/// Expressions of numbers alone are folded into a single `Token::Number`.
+pub fn parse_immediate(input: &str) -> nom::IResult<&str, Token> {
+    let (rem, terms) = parse_sum(false)(input)?;
+    let numbers: Option<i64> = terms
+        .iter()
+        .map(|(coefficient, term)| match term {
+            Token::Number { num } => Some(*coefficient as i64 * *num as i64),
+            _ => None,
+        })
+        .sum();
+    let token = match (numbers, terms.as_slice()) {
+        (Some(sum), _) => match i32::try_from(sum) {
+            Ok(num) => Token::Number { num },
+            Err(_) => {
+                return Err(nom::Err::Error(nom::error::Error::new(
+                    input,
+                    nom::error::ErrorKind::TooLarge,
+                )))
+            }
+        },
+        (None, [(1, Token::Label(_))]) => terms.into_iter().next().unwrap().1,
+        (None, _) => Token::Expression(terms),
+    };
+
+    Ok((rem, token))
+}
+
+pub fn parse_target(input: &str) -> nom::IResult<&str, Token> {
+    alt((parse_register, parse_number, parse_label))(input)
+}
+
+pub fn parse_label_definition(input: &str) -> nom::IResult<&str, String> {
+    let (rem, name) = terminated(parse_identifier, tag(":"))(input)?;
+
+    Ok((rem, name.to_string()))
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Instruction {
     opcode: Opcode,
@@ -60,35 +338,169 @@ pub struct Instruction {
 }
 
 impl Instruction {
-    pub fn to_bytes(&self) -> Vec<u8> {
+    /// Encodes the instruction, resolving labels through `symbols`
+    ///
+    /// Fails if a label is missing from `symbols`, or if a number or label does not fit its
+    /// 16-bit operand: signed for immediates, unsigned for jump addresses.
+    pub fn to_bytes(&self, symbols: &HashMap<String, usize>) -> Result<Vec<u8>, AssembleError> {
         let mut bytes = vec![self.opcode.into()];
+        let operand_types = self.opcode.operands().operands;
 
-        for operand in &self.operands {
+        for (operand, operand_type) in self.operands.iter().zip(operand_types) {
             match operand {
-                Token::Register { reg } => bytes.push(*reg),
-                Token::Number { num } => {
-                    let converted = *num as u16;
-                    let byte1 = converted;
-                    let byte2 = converted >> 8;
-                    bytes.push(byte2 as u8);
-                    bytes.push(byte1 as u8);
+                Token::Register { reg } => {
+                    bytes.push(*reg);
+                    if operand_type == OperandType::Target {
+                        bytes.extend([0, 0]);
+                    }
+                }
+                Token::Number { .. } | Token::Expression(_) | Token::Label(_) => {
+                    // Outside of jumps a label is just its address as a number
+                    if operand_type == OperandType::Target {
+                        bytes.push(IMMEDIATE_TARGET);
+                    }
+                    let num = operand.evaluate(symbols)?;
+                    let out_of_range = || AssembleError::OutOfRange(self.to_string(), num);
+                    // Immediates are signed, jump addresses unsigned, both 16 bits wide
+                    if operand_type == OperandType::Target {
+                        let address = u16::try_from(num).map_err(|_| out_of_range())?;
+                        bytes.extend(address.to_be_bytes());
+                    } else {
+                        let immediate = i16::try_from(num).map_err(|_| out_of_range())?;
+                        bytes.extend(immediate.to_be_bytes());
+                    }
+                }
+                Token::RegisterList { regs } => {
+                    bytes.push(regs.len() as u8);
+                    bytes.extend(regs);
                 }
                 _ => unimplemented!(),
             }
         }
+        // Opcodes such as `NOP` reserve more bytes than their operands fill
+        if bytes.len() < self.opcode.size() {
+            bytes.resize(self.opcode.size(), 0);
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn size(&self) -> usize {
+        let listed: usize = self
+            .operands
+            .iter()
+            .map(|operand| match operand {
+                Token::RegisterList { regs } => regs.len(),
+                _ => 0,
+            })
+            .sum();
+        self.opcode.size() + listed
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        self.opcode
+    }
+
+    pub fn operands(&self) -> &[Token] {
+        &self.operands
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.opcode.mnemonic())?;
+        for operand in &self.operands {
+            write!(f, " {}", operand)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AssembleError {
+    Parse(ParseError),
+    UndefinedLabel(String),
+    /// A label or constant defined a second time, with the 1-based line of the second
+    /// definition
+    DuplicateLabel(String, usize),
+    Io(String),
+    /// A source file that is not valid UTF-8
+    InvalidEncoding(PathBuf),
+    /// A file that ends up including itself
+    IncludeCycle(PathBuf),
+    /// Data outside of `.data` or an instruction inside it, with the 1-based line
+    WrongSection(usize),
+    /// A number too wide for its operand, with the instruction it appears in
+    OutOfRange(String, i32),
+}
 
-        bytes
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssembleError::Parse(e) => write!(f, "Parse error on {}", e),
+            AssembleError::UndefinedLabel(name) => write!(f, "Undefined label: {}", name),
+            AssembleError::DuplicateLabel(name, line) => {
+                write!(f, "{} defined again on line {}", name, line)
+            }
+            AssembleError::Io(e) => write!(f, "IO error: {}", e),
+            AssembleError::InvalidEncoding(path) => {
+                write!(f, "{} is not valid UTF-8", path.display())
+            }
+            AssembleError::IncludeCycle(path) => {
+                write!(f, "{} includes itself", path.display())
+            }
+            AssembleError::WrongSection(line) => {
+                write!(f, "Statement on line {} is in the wrong section", line)
+            }
+            AssembleError::OutOfRange(instruction, num) => {
+                write!(f, "{} does not fit in 16 bits in `{}`", num, instruction)
+            }
+        }
     }
 }
 
+impl std::error::Error for AssembleError {}
+
+#[derive(Debug)]
 pub struct Program {
     instructions: Vec<Instruction>,
+    symbols: HashMap<String, usize>,
+    data: Vec<u8>,
+    data_symbols: HashMap<String, usize>,
+    constants: HashMap<String, i32>,
+    // Code offset of each code label or instruction written in the assembled source, by line,
+    // with the index of the instruction if it is one
+    listed_lines: HashMap<usize, (usize, Option<usize>)>,
+}
+
+/// Programs are equal when they assemble to the same bytecode and data, however their labels
+/// and constants are named
+impl PartialEq for Program {
+    fn eq(&self, other: &Self) -> bool {
+        let same_code = match (self.to_bytes(), other.to_bytes()) {
+            (Ok(bytes), Ok(other_bytes)) => bytes == other_bytes,
+            // Without bytes to compare, e.g. a label is undefined, fall back to the source
+            _ => self.instructions == other.instructions,
+        };
+        same_code && self.data == other.data
+    }
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Program {
     pub fn new() -> Program {
         Program {
             instructions: vec![],
+            symbols: HashMap::new(),
+            data: vec![],
+            data_symbols: HashMap::new(),
+            constants: HashMap::new(),
+            listed_lines: HashMap::new(),
         }
     }
 
@@ -96,43 +508,369 @@ impl Program {
         self.instructions.push(instruction);
     }
 
-    // TODO: normal error
-    pub fn from_str(input: &str) -> Result<Program, String> {
-        let (_, instructions) = parse_assembly(input).map_err(|e| e.to_string())?;
+    /// Number of instructions in the program
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Instruction> {
+        self.instructions.iter()
+    }
+
+    /// Defines a label pointing at the byte offset of the next added instruction
+    pub fn add_label(&mut self, name: String) {
+        let offset = self.instructions.iter().map(Instruction::size).sum();
+        self.symbols.insert(name, offset);
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(input: &str) -> Result<Program, AssembleError> {
+        let statements = parse_source(input).map_err(AssembleError::Parse)?;
+        Program::assemble(statements, Path::new("."), &mut vec![])
+    }
+
+    /// Assembles a program one line at a time, e.g. from `BufRead::lines`
+    pub fn from_lines(lines: impl Iterator<Item = String>) -> Result<Program, AssembleError> {
+        let mut statements = vec![];
+        for (i, line) in lines.enumerate() {
+            if let Some(statement) = parse_line(i + 1, &line).map_err(AssembleError::Parse)? {
+                statements.push((i + 1, statement));
+            }
+        }
+        Program::assemble(statements, Path::new("."), &mut vec![])
+    }
+
+    // Expands the includes of the source's own `statements` and assembles the result, keeping
+    // track of which statements the source wrote itself for `listing`
+    fn assemble(
+        statements: Vec<(usize, Statement)>,
+        base: &Path,
+        including: &mut Vec<PathBuf>,
+    ) -> Result<Program, AssembleError> {
+        let mut expanded = vec![];
+        for (line, statement) in statements {
+            let listed = !matches!(statement, Statement::Include(_));
+            for (line, statement) in expand_includes(vec![(line, statement)], base, including)? {
+                expanded.push((line, statement, listed));
+            }
+        }
+        Program::from_statements(expanded)
+    }
+
+    // Collects labels and checks label uses of statements paired with their line numbers and
+    // whether they come from the assembled source rather than an include
+    fn from_statements(
+        statements: Vec<(usize, Statement, bool)>,
+    ) -> Result<Program, AssembleError> {
+        let mut program = Program::new();
+        let mut section = Section::Code;
+        let mut offset = 0;
+        for (line, statement, listed) in statements {
+            match &statement {
+                Statement::Label(_) if listed && section == Section::Code => {
+                    program.listed_lines.insert(line, (offset, None));
+                }
+                Statement::Instruction(instruction) if section == Section::Code => {
+                    if listed {
+                        let index = program.instructions.len();
+                        program.listed_lines.insert(line, (offset, Some(index)));
+                    }
+                    offset += instruction.size();
+                }
+                _ => {}
+            }
+            match (statement, section) {
+                (Statement::Label(name) | Statement::Const(name, _), _)
+                    if program.is_defined(&name) =>
+                {
+                    return Err(AssembleError::DuplicateLabel(name, line));
+                }
+                (Statement::Const(name, value), _) => {
+                    program.constants.insert(name, value);
+                }
+                (Statement::Label(name), Section::Code) => program.add_label(name),
+                (Statement::Label(name), Section::Data) => {
+                    program.data_symbols.insert(name, program.data.len());
+                }
+                (Statement::Instruction(instruction), Section::Code) => {
+                    program.add_instruction(instruction)
+                }
+                (Statement::Data(bytes), Section::Data) => program.data.extend(bytes),
+                (Statement::Section(next), _) => section = next,
+                (Statement::Include(_), _) => unreachable!("includes are expanded beforehand"),
+                _ => return Err(AssembleError::WrongSection(line)),
+            }
+        }
+
+        // Constants may be used before they are defined too, so they are substituted last
+        for instruction in &mut program.instructions {
+            for operand in &mut instruction.operands {
+                operand.substitute(&program.constants);
+            }
+        }
+
+        // Labels may be used before they are defined, so they are checked once all are known
+        for instruction in &program.instructions {
+            for name in instruction.operands.iter().flat_map(Token::labels) {
+                if !program.is_defined(name) {
+                    return Err(AssembleError::UndefinedLabel(name.to_string()));
+                }
+            }
+        }
+
+        Ok(program)
+    }
+
+    fn is_defined(&self, label: &str) -> bool {
+        self.symbols.contains_key(label)
+            || self.data_symbols.contains_key(label)
+            || self.constants.contains_key(label)
+    }
+
+    /// Byte offset in `to_bytes` of the code label `name`, e.g. to start running there
+    pub fn label_address(&self, name: &str) -> Option<usize> {
+        self.symbols.get(name).copied()
+    }
+
+    /// Initial heap contents from the `.data` section, see `Vm::with_program_and_data`
+    pub fn data_image(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    /// Decodes bytecode produced by `to_bytes` back into a program
+    ///
+    /// Jumps to labels decode to jumps to the address the label resolved to.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, DecodeError> {
+        Program::decode(bytes, Encoding::Packed)
+    }
+
+    /// Decodes bytecode produced by `to_bytes_padded`, skipping the padding
+    pub fn from_bytes_padded(bytes: &[u8]) -> Result<Program, DecodeError> {
+        Program::decode(bytes, Encoding::Padded)
+    }
+
+    fn decode(bytes: &[u8], encoding: Encoding) -> Result<Program, DecodeError> {
         let mut program = Program::new();
-        for instruction in instructions {
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let instruction = decode_instruction(bytes, offset)?;
+            offset += encoding.width(instruction.size());
             program.add_instruction(instruction);
         }
         Ok(program)
     }
 
-    pub fn from_file(file_path: impl AsRef<std::path::Path>) -> Result<Program, String> {
-        let input = std::fs::read_to_string(file_path).map_err(|e| e.to_string())?;
-        Program::from_str(&input)
+    /// Pairs every line of `source`, the text this program was assembled from, with its byte
+    /// offset and encoded bytes
+    ///
+    /// The offsets are the ones recorded while assembling. Instructions pulled in by `.include`
+    /// are not listed, but still move the offsets of the lines after it. Lines in the `.data`
+    /// section have no code offset.
+    pub fn listing(&self, source: &str) -> String {
+        let symbols = self.resolved_symbols(Encoding::Packed);
+        let mut output = String::new();
+        for (i, line) in source.lines().enumerate() {
+            let entry = match self.listed_lines.get(&(i + 1)) {
+                Some(&(offset, instruction)) => {
+                    // An instruction that does not encode is listed without bytes
+                    let bytes: Vec<String> = instruction
+                        .and_then(|index| self.instructions[index].to_bytes(&symbols).ok())
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|byte| format!("{:02X}", byte))
+                        .collect();
+                    format!("{:04} {:<12}", offset, bytes.join(" "))
+                }
+                None => format!("{:17}", ""),
+            };
+            output.push_str(format!("{} {}", entry, line).trim_end());
+            output.push('\n');
+        }
+        output
+    }
+
+    /// Assembles a file, resolving `.include` paths relative to the including file
+    pub fn from_file(file_path: impl AsRef<Path>) -> Result<Program, AssembleError> {
+        let (path, statements) = read_source(&Path::new(".").join(file_path))?;
+        let base = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        Program::assemble(statements, &base, &mut vec![path])
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, AssembleError> {
+        self.encode(Encoding::Packed)
+    }
+
+    /// Encodes every instruction zero-padded to 4 bytes, the layout the `* 4` of jumps to a
+    /// register assumes, for a VM running with `Encoding::Padded`
+    pub fn to_bytes_padded(&self) -> Result<Vec<u8>, AssembleError> {
+        self.encode(Encoding::Padded)
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
+    fn encode(&self, encoding: Encoding) -> Result<Vec<u8>, AssembleError> {
+        let symbols = self.resolved_symbols(encoding);
         let mut bytes = vec![];
         for instruction in &self.instructions {
-            bytes.extend(instruction.to_bytes());
+            let mut instruction_bytes = instruction.to_bytes(&symbols)?;
+            instruction_bytes.resize(encoding.width(instruction_bytes.len()), 0);
+            bytes.extend(instruction_bytes);
+        }
+        Ok(bytes)
+    }
+
+    // Addresses of code labels under `encoding` and heap offsets of data labels
+    fn resolved_symbols(&self, encoding: Encoding) -> HashMap<String, usize> {
+        // Labels are recorded as packed offsets, move them to where their instruction lands
+        let mut addresses = HashMap::new();
+        let mut packed = 0;
+        let mut encoded = 0;
+        for instruction in &self.instructions {
+            addresses.insert(packed, encoded);
+            packed += instruction.size();
+            encoded += encoding.width(instruction.size());
+        }
+        addresses.insert(packed, encoded);
+
+        let code = self
+            .symbols
+            .iter()
+            .map(|(name, offset)| (name.clone(), addresses[offset]));
+        let data = self
+            .data_symbols
+            .iter()
+            .map(|(name, offset)| (name.clone(), *offset));
+        code.chain(data).collect()
+    }
+}
+
+impl<'a> IntoIterator for &'a Program {
+    type Item = &'a Instruction;
+    type IntoIter = std::slice::Iter<'a, Instruction>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.instructions.iter()
+    }
+}
+
+/// Assembles `src`, runs it with its `.data` on the heap and returns the VM it stopped in
+pub fn assemble_and_run(src: &str) -> Result<Vm, AssembleError> {
+    let program = Program::from_str(src)?;
+    let mut vm = Vm::with_program_and_data(program.to_bytes()?, program.data_image());
+    vm.run();
+    Ok(vm)
+}
+
+// Replaces every `.include` in `statements` by the statements of the included file
+//
+// `including` holds the files currently being expanded, to detect include cycles.
+fn expand_includes(
+    statements: Vec<(usize, Statement)>,
+    base: &Path,
+    including: &mut Vec<PathBuf>,
+) -> Result<Vec<(usize, Statement)>, AssembleError> {
+    let mut expanded = vec![];
+    for (line, statement) in statements {
+        let Statement::Include(path) = statement else {
+            expanded.push((line, statement));
+            continue;
+        };
+
+        let (path, statements) = read_source(&base.join(path))?;
+        if including.contains(&path) {
+            return Err(AssembleError::IncludeCycle(path));
         }
-        bytes
+
+        including.push(path.clone());
+        let base = path.parent().unwrap_or(Path::new("."));
+        expanded.extend(expand_includes(statements, base, including)?);
+        including.pop();
     }
+    Ok(expanded)
+}
+
+// Reads and parses the file at `path`, along with its canonical path
+fn read_source(path: &Path) -> Result<(PathBuf, Vec<(usize, Statement)>), AssembleError> {
+    let io_error = |e: std::io::Error| AssembleError::Io(format!("{}: {}", path.display(), e));
+    let path = path.canonicalize().map_err(io_error)?;
+    let input = std::fs::read(&path).map_err(io_error)?;
+    let input =
+        String::from_utf8(input).map_err(|_| AssembleError::InvalidEncoding(path.clone()))?;
+    let statements = parse_source(&input).map_err(AssembleError::Parse)?;
+    Ok((path, statements))
+}
+
+pub fn parse_include(input: &str) -> nom::IResult<&str, String> {
+    let (rem, path) = preceded(
+        pair(tag(".include"), space1),
+        delimited(char('"'), take_until("\""), char('"')),
+    )(input)?;
+
+    Ok((rem, path.to_string()))
+}
+
+pub fn parse_section(input: &str) -> nom::IResult<&str, Section> {
+    alt((
+        value(Section::Data, tag(".data")),
+        value(Section::Code, tag(".code")),
+    ))(input)
+}
+
+// `.const NAME value`, where the value may be an expression of numbers
+pub fn parse_const(input: &str) -> nom::IResult<&str, (String, i32)> {
+    let (rem, (name, value)) = preceded(
+        pair(tag(".const"), space1),
+        separated_pair(
+            parse_identifier,
+            space1,
+            map_opt(parse_immediate, |token| match token {
+                Token::Number { num } => Some(num),
+                _ => None,
+            }),
+        ),
+    )(input)?;
+
+    Ok((rem, (name.to_string(), value)))
+}
+
+// `.string "text"` or `.bytes 1 2 3`
+pub fn parse_data(input: &str) -> nom::IResult<&str, Vec<u8>> {
+    alt((
+        preceded(
+            pair(tag(".string"), space1),
+            map(
+                delimited(char('"'), take_until("\""), char('"')),
+                |text: &str| text.as_bytes().to_vec(),
+            ),
+        ),
+        preceded(
+            pair(tag(".bytes"), space1),
+            separated_list1(space1, nom::character::complete::u8),
+        ),
+    ))(input)
 }
 
 pub fn parse_instruction(input: &str) -> nom::IResult<&str, Instruction> {
-    let (rem, opcode) = parse_opcode(input)?;
-    let (mut rem, _) = space1(rem)?;
+    let (mut rem, opcode) = parse_opcode(input)?;
 
     let opcode_operands = opcode.operands().operands;
     let mut operands = vec![];
 
+    // Operands are separated from the mnemonic, instructions without any stand alone
+    if !opcode_operands.is_empty() {
+        (rem, _) = space1(rem)?;
+    }
+
     for operand in opcode_operands {
         // Gobble spaces
         let (rem_local, _) = multispace0(rem)?;
         let (rem_local, operand) = match operand {
             OperandType::Register => parse_register(rem_local),
-            OperandType::Number => parse_number(rem_local),
+            OperandType::Number => parse_immediate(rem_local),
+            OperandType::Target => parse_target(rem_local),
+            OperandType::RegisterList => parse_register_list(rem_local),
         }?;
         operands.push(operand);
 
@@ -142,28 +880,102 @@ pub fn parse_instruction(input: &str) -> nom::IResult<&str, Instruction> {
     Ok((rem, Instruction { opcode, operands }))
 }
 
-// Get one line without new line characters
-pub fn parse_lines(input: &str) -> nom::IResult<&str, Vec<&str>> {
-    many1(map(terminated(take_until("\n"), newline), |line: &str| {
-        line
-    }))(input)
+/// Cuts `line` off at a `;` comment marker outside of a string literal
+pub fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => return line[..i].trim_end(),
+            _ => {}
+        }
+    }
+    line
+}
+
+// Get the lines holding more than whitespace or a comment, without new line characters or
+// comments and paired with their 1-based line number; the last one may lack its newline
+pub fn parse_lines(input: &str) -> nom::IResult<&str, Vec<(usize, &str)>> {
+    let last_line = verify(rest, |line: &str| !line.is_empty());
+    let (rem, lines) = many0(map(
+        alt((terminated(take_until("\n"), newline), last_line)),
+        strip_comment,
+    ))(input)?;
+
+    let lines = lines
+        .into_iter()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| !line.trim().is_empty())
+        .collect();
+    Ok((rem, lines))
+}
+
+// Surrounding whitespace is ignored, anything else left over is an error
+pub fn parse_statement(input: &str) -> nom::IResult<&str, Statement> {
+    all_consuming(delimited(
+        space0,
+        alt((
+            map(parse_label_definition, Statement::Label),
+            map(parse_include, Statement::Include),
+            map(parse_section, Statement::Section),
+            map(parse_data, Statement::Data),
+            map(parse_const, |(name, value)| Statement::Const(name, value)),
+            map(parse_instruction, Statement::Instruction),
+        )),
+        space0,
+    ))(input)
+}
+
+// Parses one line of source, `None` if it holds nothing but whitespace or a comment
+fn parse_line(line: usize, text: &str) -> Result<Option<Statement>, ParseError> {
+    let text = strip_comment(text);
+    if text.trim().is_empty() {
+        return Ok(None);
+    }
+    match parse_statement(text) {
+        Ok((_, statement)) => Ok(Some(statement)),
+        Err(_) => Err(parse_error::diagnose(line, text)),
+    }
+}
+
+// Like `parse_numbered_assembly`, but reports where and why a line does not parse
+fn parse_source(input: &str) -> Result<Vec<(usize, Statement)>, ParseError> {
+    let (_, lines) = parse_lines(input).unwrap_or_default();
+    let mut statements = vec![];
+    for (number, line) in lines {
+        if let Some(statement) = parse_line(number, line)? {
+            statements.push((number, statement));
+        }
+    }
+    Ok(statements)
+}
+
+pub fn parse_assembly(input: &str) -> nom::IResult<&str, Vec<Statement>> {
+    let (rem, statements) = parse_numbered_assembly(input)?;
+
+    Ok((
+        rem,
+        statements
+            .into_iter()
+            .map(|(_, statement)| statement)
+            .collect(),
+    ))
 }
 
-pub fn parse_assembly(input: &str) -> nom::IResult<&str, Vec<Instruction>> {
+/// Like `parse_assembly`, but pairs every statement with its 1-based line number
+pub fn parse_numbered_assembly(input: &str) -> nom::IResult<&str, Vec<(usize, Statement)>> {
     let (rem, lines) = parse_lines(input)?;
 
-    let mut instructions = vec![];
+    let mut statements = vec![];
 
-    for (i, line) in lines.iter().enumerate() {
-        let (_, instruction) = parse_instruction(line).map_err(|e| {
-            println!("Error on line {}: {}", i, e);
-            e
-        })?;
+    for (number, line) in lines {
+        let (_, statement) = parse_statement(line)?;
 
-        instructions.push(instruction);
+        statements.push((number, statement));
     }
 
-    Ok((rem, instructions))
+    Ok((rem, statements))
 }
 
 #[cfg(test)]
@@ -176,12 +988,57 @@ mod tests {
         assert_eq!(result, Ok((" $0 1", Opcode::Load)));
     }
 
+    #[test]
+    fn test_parse_opcode_whole_words() {
+        assert_eq!(parse_opcode("LTQ $0 $1"), Ok((" $0 $1", Opcode::Ltq)));
+        assert_eq!(parse_opcode("GTQ $0 $1"), Ok((" $0 $1", Opcode::Gtq)));
+        assert_eq!(parse_opcode("LT $0 $1"), Ok((" $0 $1", Opcode::Lt)));
+        assert_eq!(parse_opcode("GT"), Ok(("", Opcode::Gt)));
+        assert!(parse_opcode("GTE $0 $1").is_err());
+        assert!(parse_opcode("HLTX").is_err());
+    }
+
+    #[test]
+    fn test_parse_opcode_aliases() {
+        let aliases = [
+            ("GE", "GTQ"),
+            ("LE", "LTQ"),
+            ("NE", "NEQ"),
+            ("LOADB", "LOADM"),
+        ];
+        for (alias, canonical) in aliases {
+            let aliased = Program::from_str(&format!("{} $0 $1\n", alias)).unwrap();
+            let expected = Program::from_str(&format!("{} $0 $1\n", canonical)).unwrap();
+
+            assert_eq!(
+                aliased.to_bytes().unwrap(),
+                expected.to_bytes().unwrap(),
+                "{}",
+                alias
+            );
+        }
+    }
+
     #[test]
     fn test_parse_register() {
         let result = parse_register("$0 1");
         assert_eq!(result, Ok((" 1", Token::Register { reg: 0 })));
     }
 
+    #[test]
+    fn test_parse_register_out_of_range() {
+        assert_eq!(parse_register("$31"), Ok(("", Token::Register { reg: 31 })));
+        assert!(parse_register("$32").is_err());
+        assert!(parse_register("$200").is_err());
+        assert!(matches!(
+            Program::from_str("LOAD $200 1\n"),
+            Err(AssembleError::Parse(ParseError {
+                kind: ParseErrorKind::RegisterOutOfRange,
+                ..
+            }))
+        ));
+    }
+
     #[test]
     fn test_parse_number() {
         let result = parse_number("01");
@@ -189,47 +1046,825 @@ mod tests {
     }
 
     #[test]
-    fn test_parse_instruction() {
-        let result = parse_instruction("LOAD $0 1");
-
+    fn test_parse_number_separators() {
+        assert_eq!(parse_number("1_000"), Ok(("", Token::Number { num: 1000 })));
         assert_eq!(
-            result,
-            Ok((
-                "",
-                Instruction {
-                    opcode: Opcode::Load,
-                    operands: vec![Token::Register { reg: 0 }, Token::Number { num: 1 }]
-                }
-            ))
+            parse_number("1_000_000"),
+            Ok(("", Token::Number { num: 1_000_000 }))
         );
+        assert_eq!(parse_number("+7"), Ok(("", Token::Number { num: 7 })));
+        assert!(parse_number("1__0").is_err());
+        assert!(parse_number("_5").is_err());
+        assert!(parse_number("10_").is_err());
+        assert!(parse_number("++7").is_err());
     }
 
     #[test]
-    fn test_parse_lines() {
-        let result = parse_lines("LOAD $0 1\nLOAD $1 2\n");
+    fn test_parse_negative_number() {
+        assert_eq!(parse_number("-5"), Ok(("", Token::Number { num: -5 })));
+        assert_eq!(parse_number("-0x10"), Ok(("", Token::Number { num: -16 })));
+        assert!(parse_number("--5").is_err());
+        assert!(parse_number("-").is_err());
+    }
+
+    #[test]
+    fn test_out_of_range_number() {
+        let program = Program::from_str("LOAD $0 70000\n").unwrap();
+        let error = program.to_bytes().unwrap_err();
+        assert_eq!(
+            error,
+            AssembleError::OutOfRange("LOAD $0 70000".to_string(), 70000)
+        );
+        assert_eq!(
+            error.to_string(),
+            "70000 does not fit in 16 bits in `LOAD $0 70000`"
+        );
 
-        assert_eq!(result, Ok(("", vec!["LOAD $0 1", "LOAD $1 2"])));
+        let program = Program::from_str("LOAD $0 -32768\nLOAD $1 32767\nJMP 65535\n").unwrap();
+        assert!(program.to_bytes().is_ok());
+        assert!(Program::from_str("LOAD $0 32768\n")
+            .unwrap()
+            .to_bytes()
+            .is_err());
+        assert!(Program::from_str("JMP 65536\n")
+            .unwrap()
+            .to_bytes()
+            .is_err());
     }
 
     #[test]
-    fn test_parse_assembly() {
-        let result = parse_assembly("LOAD $0 1\nLOAD $1 2\n");
+    fn test_out_of_range_label() {
+        // 9000 NOPs put `far` at byte 36000, a valid jump address but not a valid immediate
+        let nops = "NOP\n".repeat(9000);
+        let program = Program::from_str(&format!("{nops}far:\nJMP far\n")).unwrap();
+        assert!(program.to_bytes().is_ok());
 
+        let program = Program::from_str(&format!("{nops}far:\nLOAD $2 far\n")).unwrap();
         assert_eq!(
-            result,
-            Ok((
-                "",
-                vec![
-                    Instruction {
-                        opcode: Opcode::Load,
-                        operands: vec![Token::Register { reg: 0 }, Token::Number { num: 1 }]
-                    },
-                    Instruction {
+            program.to_bytes().unwrap_err(),
+            AssembleError::OutOfRange("LOAD $2 far".to_string(), 36000)
+        );
+    }
+
+    #[test]
+    fn test_negative_operand_round_trip() {
+        let program = Program::from_str("LOAD $0 -5\nHLT\n").unwrap();
+        let bytes = program.to_bytes().unwrap();
+        assert_eq!(bytes, vec![1, 0, 0xFF, 0xFB, 0]);
+        assert_eq!(Program::from_bytes(&bytes), Ok(program));
+
+        let mut vm = crate::vm::Vm::with_program(bytes);
+        vm.run();
+        assert_eq!(vm.registers[0], -5);
+    }
+
+    #[test]
+    fn test_parse_number_radix() {
+        assert_eq!(parse_number("0xFF"), Ok(("", Token::Number { num: 255 })));
+        assert_eq!(parse_number("0x1f"), Ok(("", Token::Number { num: 31 })));
+        assert_eq!(parse_number("0b1010"), Ok(("", Token::Number { num: 10 })));
+        assert_eq!(
+            parse_number("0b1111_0000"),
+            Ok(("", Token::Number { num: 240 }))
+        );
+        assert!(parse_number("0xFG").is_err());
+        assert!(parse_number("0b102").is_err());
+        assert!(parse_number("0x").is_err());
+        assert!(parse_number("12abc").is_err());
+    }
+
+    #[test]
+    fn test_mixed_radix_program() {
+        let program = Program::from_str("LOAD $0 0xFF\nLOAD $1 0b1010\nLOAD $2 42\n").unwrap();
+        let expected = Program::from_str("LOAD $0 255\nLOAD $1 10\nLOAD $2 42\n").unwrap();
+
+        assert_eq!(program, expected);
+        assert_eq!(&program.to_bytes().unwrap()[..4], &[1, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_parse_immediate() {
+        assert_eq!(parse_immediate("(2+3)"), Ok(("", Token::Number { num: 5 })));
+        assert_eq!(
+            parse_immediate("(10 - 2 - 3)"),
+            Ok(("", Token::Number { num: 5 }))
+        );
+        assert_eq!(
+            parse_immediate("1-(2+3)"),
+            Ok(("", Token::Number { num: -4 }))
+        );
+        assert_eq!(parse_immediate("7"), Ok(("", Token::Number { num: 7 })));
+        assert_eq!(
+            parse_immediate("BASE"),
+            Ok(("", Token::Label("BASE".to_string())))
+        );
+        assert_eq!(
+            parse_immediate("BASE+4"),
+            Ok((
+                "",
+                Token::Expression(vec![
+                    (1, Token::Label("BASE".to_string())),
+                    (1, Token::Number { num: 4 })
+                ])
+            ))
+        );
+        // Outside of parentheses a space ends the operand
+        assert_eq!(
+            parse_immediate("2 + 3"),
+            Ok((" + 3", Token::Number { num: 2 }))
+        );
+        assert!(parse_immediate("(2+3").is_err());
+    }
+
+    #[test]
+    fn test_immediate_expressions() {
+        let vm = assemble_and_run(
+            ".data\npad:\n.bytes 1 2\nBASE:\n.bytes 3\n.code\n\
+             LOAD $0 BASE+4\nLOAD $1 (BASE - 1)\nLOAD $2 (2+3)\nHLT\n",
+        )
+        .unwrap();
+        assert_eq!(vm.registers[..3], [6, 1, 5]);
+
+        let program = Program::from_str("LOAD $0 BASE+4\nBASE:\nHLT\n").unwrap();
+        assert_eq!(program.instructions[0].to_string(), "LOAD $0 BASE+4");
+        assert_eq!(
+            Program::from_str("LOAD $0 MISSING-1\n"),
+            Err(AssembleError::UndefinedLabel("MISSING".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_instruction() {
+        let result = parse_instruction("LOAD $0 1");
+
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Instruction {
+                    opcode: Opcode::Load,
+                    operands: vec![Token::Register { reg: 0 }, Token::Number { num: 1 }]
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_lines() {
+        let result = parse_lines("LOAD $0 1\nLOAD $1 2\n");
+
+        assert_eq!(result, Ok(("", vec![(1, "LOAD $0 1"), (2, "LOAD $1 2")])));
+    }
+
+    #[test]
+    fn test_parse_lines_keeps_line_numbers() {
+        assert_eq!(
+            parse_lines("LOAD $0 1\n\n; comment\n  HLT ; done\n"),
+            Ok(("", vec![(1, "LOAD $0 1"), (4, "  HLT")]))
+        );
+
+        match Program::from_str("LOAD $0 1\n\nLOAD $x 2\n") {
+            Err(AssembleError::Parse(e)) => assert_eq!(e.line, 3),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_lines_without_final_newline() {
+        assert_eq!(
+            parse_lines("LOAD $0 1\nLOAD $1 2"),
+            Ok(("", vec![(1, "LOAD $0 1"), (2, "LOAD $1 2")]))
+        );
+    }
+
+    #[test]
+    fn test_single_line_program() {
+        let with_newline = Program::from_str("LOAD $0 1\n").unwrap();
+        let without_newline = Program::from_str("LOAD $0 1").unwrap();
+
+        assert_eq!(without_newline.len(), 1);
+        assert_eq!(with_newline, without_newline);
+    }
+
+    #[test]
+    fn test_parse_assembly() {
+        let result = parse_assembly("LOAD $0 1\nLOAD $1 2\n");
+
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                vec![
+                    Statement::Instruction(Instruction {
+                        opcode: Opcode::Load,
+                        operands: vec![Token::Register { reg: 0 }, Token::Number { num: 1 }]
+                    }),
+                    Statement::Instruction(Instruction {
                         opcode: Opcode::Load,
                         operands: vec![Token::Register { reg: 1 }, Token::Number { num: 2 }]
-                    }
+                    })
                 ]
             ))
         );
     }
+
+    #[test]
+    fn test_parse_label_operand() {
+        let result = parse_instruction("JMP loop");
+
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Instruction {
+                    opcode: Opcode::Jmp,
+                    operands: vec![Token::Label("loop".to_string())]
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_address_operand() {
+        let program = Program::from_str("JMP 12\n").unwrap();
+
+        assert_eq!(
+            program.to_bytes().unwrap(),
+            vec![6, IMMEDIATE_TARGET, 0, 12]
+        );
+    }
+
+    #[test]
+    fn test_resolve_label() {
+        let program = Program::from_str("LOAD $0 1\nloop:\nJMP loop\n").unwrap();
+
+        assert_eq!(
+            program.to_bytes().unwrap(),
+            vec![1, 0, 0, 1, 6, IMMEDIATE_TARGET, 0, 4]
+        );
+    }
+
+    #[test]
+    fn test_undefined_label() {
+        let result = Program::from_str("JMP nowhere\n");
+
+        assert_eq!(
+            result,
+            Err(AssembleError::UndefinedLabel("nowhere".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_undefined_label_in_built_program() {
+        let mut program = Program::new();
+        program.add_instruction(parse_instruction("JMP nowhere").unwrap().1);
+
+        assert_eq!(
+            program.to_bytes(),
+            Err(AssembleError::UndefinedLabel("nowhere".to_string()))
+        );
+        assert_eq!(
+            parse_instruction("LOAD $0 nowhere + 1")
+                .unwrap()
+                .1
+                .to_bytes(&HashMap::new()),
+            Err(AssembleError::UndefinedLabel("nowhere".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_duplicate_label() {
+        let result = Program::from_str("loop:\nLOAD $0 1\nloop:\nJMP loop\n");
+
+        assert_eq!(
+            result,
+            Err(AssembleError::DuplicateLabel("loop".to_string(), 3))
+        );
+    }
+
+    #[test]
+    fn test_empty_program() {
+        let program = Program::from_str("").unwrap();
+
+        assert!(program.to_bytes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_blank_lines_between_instructions() {
+        let program =
+            Program::from_str("\n\nLOAD $0 1\n\nLOAD $1 2\n  \nADD $0 $1 $2\n\n").unwrap();
+        let expected = Program::from_str("LOAD $0 1\nLOAD $1 2\nADD $0 $1 $2\n").unwrap();
+
+        assert_eq!(program, expected);
+        assert_eq!(program.len(), 3);
+    }
+
+    #[test]
+    fn test_surrounding_whitespace() {
+        let program = Program::from_str(
+            "\n \n\tloop:  \n  LOAD $0 1   \n\nDEC $0\t\n   \nJMP loop \n.data \n  .bytes 1 2 \n",
+        )
+        .unwrap();
+        let expected =
+            Program::from_str("loop:\nLOAD $0 1\nDEC $0\nJMP loop\n.data\n.bytes 1 2\n").unwrap();
+
+        assert_eq!(program, expected);
+        assert!(matches!(
+            parse_statement("  HLT  "),
+            Ok(("", Statement::Instruction(_)))
+        ));
+    }
+
+    #[test]
+    fn test_whitespace_only_program() {
+        let program = Program::from_str("\n   \n\t\n").unwrap();
+
+        assert!(program.to_bytes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_comment_only_program() {
+        let program = Program::from_str("; nothing to see here\n  ; still nothing\n").unwrap();
+
+        assert!(program.to_bytes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_comments() {
+        let program = Program::from_str(
+            "; count down from 10\n\nLOAD $0 10 ; init counter\nloop: ; top of the loop\n  ; full-line comment\nDEC $0\nJMP loop\n",
+        )
+        .unwrap();
+        let expected = Program::from_str("LOAD $0 10\nloop:\nDEC $0\nJMP loop\n").unwrap();
+        assert_eq!(program, expected);
+
+        let lines = vec!["; header", "LOAD $0 10 ; init counter", "HLT"];
+        let program = Program::from_lines(lines.into_iter().map(String::from)).unwrap();
+        assert_eq!(program, Program::from_str("LOAD $0 10\nHLT\n").unwrap());
+    }
+
+    #[test]
+    fn test_strip_comment() {
+        assert_eq!(strip_comment("HLT ; stop"), "HLT");
+        assert_eq!(strip_comment("; only a comment"), "");
+        assert_eq!(strip_comment(".string \"a;b\" ; text"), ".string \"a;b\"");
+        assert_eq!(strip_comment("HLT"), "HLT");
+    }
+
+    #[test]
+    fn test_iterate_program() {
+        let program = Program::from_str("LOAD $0 1\nloop:\nJMP loop\n").unwrap();
+        assert_eq!(program.len(), 2);
+        assert!(!program.is_empty());
+
+        let opcodes: Vec<Opcode> = (&program).into_iter().map(Instruction::opcode).collect();
+        assert_eq!(opcodes, vec![Opcode::Load, Opcode::Jmp]);
+
+        let mut count = 0;
+        for instruction in &program {
+            assert_eq!(instruction.opcode(), opcodes[count]);
+            count += 1;
+        }
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_parse_popcnt() {
+        let program = Program::from_str("POPCNT $1 $0\n").unwrap();
+
+        assert_eq!(program.to_bytes().unwrap(), vec![20, 1, 0]);
+    }
+
+    #[test]
+    fn test_parse_operandless_instructions() {
+        let program = Program::from_str("HLTIF\nHLT\n").unwrap();
+
+        assert_eq!(program.to_bytes().unwrap(), vec![21, 0]);
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let program =
+            Program::from_str("LOAD $0 500\nLOAD $1 2\nADD $0 $1 $2\nEQ $0 $2\nJEQ $1\nHLT\n")
+                .unwrap();
+        let decoded = Program::from_bytes(&program.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(decoded, program);
+    }
+
+    #[test]
+    fn test_bytes_round_trip_labels() {
+        let program = Program::from_str("LOAD $0 1\nloop:\nJMP loop\n").unwrap();
+        let decoded = Program::from_bytes(&program.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(decoded, program);
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        let source = ".const STEP 2\nLOAD $0 500\nLOAD $1 STEP\nstart:\nSUB $0 $1 $0\nJMP start\n";
+        let program = Program::from_str(source).unwrap();
+        let start = program.label_address("start").unwrap();
+        let listing = program.disassemble(&DisassemblyOptions::default()).unwrap();
+        // Offsets become plain source again, the label defined where it was under another name
+        let mut reassembled = String::new();
+        for line in listing.lines() {
+            let (offset, text) = line.split_once(": ").unwrap();
+            if offset.parse::<usize>().unwrap() == start {
+                reassembled.push_str("again:\n");
+            }
+            reassembled.push_str(&text.replace("start", "again"));
+            reassembled.push('\n');
+        }
+
+        assert_eq!(Program::from_str(&reassembled).unwrap(), program);
+        assert_ne!(Program::from_str("LOAD $0 500\n").unwrap(), program);
+    }
+
+    #[test]
+    fn test_program_eq_undefined_label() {
+        let jump_to = |label: &str| {
+            let mut program = Program::new();
+            program.add_instruction(parse_instruction(&format!("JMP {}", label)).unwrap().1);
+            program
+        };
+
+        assert_eq!(jump_to("nowhere"), jump_to("nowhere"));
+        assert_ne!(jump_to("nowhere"), jump_to("elsewhere"));
+    }
+
+    #[test]
+    fn test_from_bytes_errors() {
+        assert_eq!(
+            Program::from_bytes(&[0, 255]),
+            Err(DecodeError::IllegalOpcode {
+                offset: 1,
+                byte: 255
+            })
+        );
+        assert_eq!(
+            Program::from_bytes(&[1, 0, 0]),
+            Err(DecodeError::Truncated {
+                offset: 0,
+                opcode: Opcode::Load
+            })
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Token::Register { reg: 3 }.to_string(), "$3");
+        assert_eq!(Token::Number { num: -42 }.to_string(), "-42");
+
+        let (_, instruction) = parse_instruction("ADD $0 $1 $2").unwrap();
+        assert_eq!(instruction.to_string(), "ADD $0 $1 $2");
+    }
+
+    #[test]
+    fn test_parse_register_list() {
+        let (_, instruction) = parse_instruction("SUMALL $0 $1 $2 $3").unwrap();
+        assert_eq!(
+            instruction.operands(),
+            &[
+                Token::Register { reg: 0 },
+                Token::RegisterList {
+                    regs: vec![1, 2, 3]
+                }
+            ]
+        );
+        assert_eq!(instruction.size(), 6);
+        assert_eq!(instruction.to_string(), "SUMALL $0 $1 $2 $3");
+
+        let program = Program::from_str("SUMALL $0 $1 $2 $3\nHLT\n").unwrap();
+        assert_eq!(program.to_bytes().unwrap(), vec![23, 0, 3, 1, 2, 3, 0]);
+        assert_eq!(
+            Program::from_bytes(&program.to_bytes().unwrap()),
+            Ok(program)
+        );
+
+        // The register count is a single byte
+        let list = |count| " $1".repeat(count);
+        let program = Program::from_str(&format!("SUMALL $0{}\n", list(255))).unwrap();
+        assert_eq!(program.to_bytes().unwrap()[2], 255);
+        match Program::from_str(&format!("SUMALL $0{}\n", list(256))) {
+            Err(AssembleError::Parse(e)) => {
+                assert_eq!((e.column, e.kind), (11, ParseErrorKind::TooManyRegisters))
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_padded_jumps() {
+        use crate::vm::{Vm, VmExit};
+
+        let program = Program::from_str(
+            "LOAD $0 1\nJMPF $0\nLOAD $1 5\nLOAD $2 7\nJMP end\nLOAD $2 9\nend:\nHLT\n",
+        )
+        .unwrap();
+        let bytes = program.to_bytes_padded().unwrap();
+        assert_eq!(bytes.len(), 7 * 4);
+        assert_eq!(&bytes[16..20], &[6, IMMEDIATE_TARGET, 0, 24]);
+
+        let mut vm = Vm::with_program(bytes.clone());
+        vm.set_encoding(Encoding::Padded);
+        assert_eq!(vm.run(), VmExit::Halted);
+        assert_eq!(vm.registers[1], 0);
+        assert_eq!(vm.registers[2], 7);
+
+        let decoded = Program::from_bytes_padded(&bytes).unwrap();
+        assert_eq!(decoded.len(), program.len());
+        assert_eq!(decoded.to_bytes_padded().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_from_lines() {
+        let lines = vec!["LOAD $0 1", "", "loop:", "JMP loop"];
+        let program = Program::from_lines(lines.into_iter().map(String::from)).unwrap();
+
+        assert_eq!(
+            program,
+            Program::from_str("LOAD $0 1\nloop:\nJMP loop\n").unwrap()
+        );
+
+        let lines = vec!["loop:", "HLT", "loop:"];
+        let result = Program::from_lines(lines.into_iter().map(String::from));
+        assert_eq!(
+            result,
+            Err(AssembleError::DuplicateLabel("loop".to_string(), 3))
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join("vm_test_include");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("helper.asm"), "double:\nADD $0 $0 $0\nHLT\n").unwrap();
+        std::fs::write(
+            dir.join("main.asm"),
+            "LOAD $0 3\nJMP double\n.include \"helper.asm\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("cycle.asm"), ".include \"cycle.asm\"\n").unwrap();
+
+        let program = Program::from_file(dir.join("main.asm"));
+        let cycle = Program::from_file(dir.join("cycle.asm"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let expected =
+            Program::from_str("LOAD $0 3\nJMP double\ndouble:\nADD $0 $0 $0\nHLT\n").unwrap();
+        assert_eq!(program, Ok(expected));
+        assert!(matches!(cycle, Err(AssembleError::IncludeCycle(_))));
+    }
+
+    #[test]
+    fn test_parse_three_register_opcodes() {
+        let (_, instruction) = parse_instruction("MULH $0 $1 $2").unwrap();
+        assert_eq!(instruction.opcode(), Opcode::Mulh);
+
+        let (_, instruction) = parse_instruction("SPACESHIP $0 $1 $2").unwrap();
+        assert_eq!(instruction.opcode(), Opcode::Spaceship);
+
+        let (_, instruction) = parse_instruction("SHL $0 $1 $2").unwrap();
+        assert_eq!(instruction.opcode(), Opcode::Shl);
+
+        let (_, instruction) = parse_instruction("SHR $0 $1 $2").unwrap();
+        assert_eq!(instruction.opcode(), Opcode::Shr);
+    }
+
+    #[test]
+    fn test_parse_cmp_opcodes() {
+        let program =
+            Program::from_str("EQ $0 $1\nNOTCMP\nINCIF $2\nLOADIF $3 7\nBT $4 $5\n").unwrap();
+
+        assert_eq!(
+            program.to_bytes().unwrap(),
+            vec![9, 0, 1, 28, 27, 2, 30, 3, 0, 7, 37, 4, 5]
+        );
+    }
+
+    #[test]
+    fn test_listing() {
+        let source = "LOAD $0 500\n\nloop:\nJMP loop\n";
+        let program = Program::from_str(source).unwrap();
+
+        assert_eq!(
+            program.listing(source),
+            "0000 01 00 01 F4  LOAD $0 500\n\
+             \n\
+             0004              loop:\n\
+             0004 06 FF 00 04  JMP loop\n"
+        );
+    }
+
+    #[test]
+    fn test_listing_include_and_data() {
+        let helper = std::env::temp_dir().join("vm_test_listing_helper.asm");
+        std::fs::write(&helper, "double:\nADD $0 $0 $0\n").unwrap();
+        let source = format!(
+            ".include \"{}\"\n.data\nvalues:\n.bytes 1 2\n.code\nstart:\nHLT\n",
+            helper.display()
+        );
+        let program = Program::from_str(&source).unwrap();
+        let listing = program.listing(&source);
+        std::fs::remove_file(&helper).unwrap();
+
+        let lines: Vec<&str> = listing.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "                  .data",
+                "                  values:",
+                "                  .bytes 1 2",
+                "                  .code",
+                "0004              start:",
+                "0004 00           HLT",
+            ]
+        );
+        assert!(listing.starts_with("                  .include"));
+
+        // Includes resolve next to the assembled file, not the working directory
+        let dir = std::env::temp_dir().join("vm_test_listing_dir");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("helper.asm"), "INC $0\nINC $1\n").unwrap();
+        let source = ".include \"helper.asm\"\nHLT\n";
+        std::fs::write(dir.join("main.asm"), source).unwrap();
+        let program = Program::from_file(dir.join("main.asm")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            program.listing(source),
+            "                  .include \"helper.asm\"\n\
+             0004 00           HLT\n"
+        );
+    }
+
+    #[test]
+    fn test_from_file_invalid_utf8() {
+        let path = std::env::temp_dir().join("vm_test_invalid_utf8.asm");
+        std::fs::write(&path, b"LOAD $0 1\n\xFF\xFE\n").unwrap();
+        let result = Program::from_file(&path);
+        let path = path.canonicalize().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(result, Err(AssembleError::InvalidEncoding(path)));
+        assert!(matches!(
+            Program::from_file("does/not/exist.asm"),
+            Err(AssembleError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_data_section() {
+        use crate::vm::{Vm, VmExit};
+
+        let program = Program::from_str(
+            ".data\nnumbers:\n.bytes 1 2\ngreeting:\n.string \"hi\"\n.code\n\
+             LOAD $0 greeting\nINC $0\nLOADM $1 $0\nHLT\n",
+        )
+        .unwrap();
+        assert_eq!(program.data_image(), vec![1, 2, b'h', b'i']);
+        assert_eq!(&program.to_bytes().unwrap()[..4], &[1, 0, 0, 2]);
+
+        let mut vm = Vm::with_program_and_data(program.to_bytes().unwrap(), program.data_image());
+        assert_eq!(vm.run(), VmExit::Halted);
+        assert_eq!(vm.registers[1], b'i' as i32);
+    }
+
+    #[test]
+    fn test_assemble_and_run() {
+        let vm = assemble_and_run("LOAD $0 6\nLOAD $1 7\nMUL $0 $1 $2\nHLT\n").unwrap();
+        assert_eq!(vm.registers[..3], [6, 7, 42]);
+        assert_eq!(vm.last_opcode(), Some(Opcode::Hlt));
+
+        assert!(matches!(
+            assemble_and_run("JMP nowhere\n"),
+            Err(AssembleError::UndefinedLabel(_))
+        ));
+    }
+
+    #[test]
+    fn test_label_address() {
+        let program =
+            Program::from_str(".data\nmessage:\n.string \"hi\"\n.code\nHLT\nmain:\nHLT\n").unwrap();
+
+        assert_eq!(program.label_address("main"), Some(1));
+        // Data labels are heap offsets, not places to run from
+        assert_eq!(program.label_address("message"), None);
+        assert_eq!(program.label_address("missing"), None);
+    }
+
+    #[test]
+    fn test_three_register_operand_order() {
+        let vm = assemble_and_run(
+            "LOAD $0 7\nLOAD $1 3\nSUB $0 $1 $2\nDIV $0 $1 $3\nSUB $1 $0 $4\nHLT\n",
+        )
+        .unwrap();
+
+        assert_eq!(vm.registers[2], 4);
+        assert_eq!(vm.registers[3], 2);
+        assert_eq!(vm.registers[4], -4);
+    }
+
+    #[test]
+    fn test_hgrow() {
+        let program = Program::from_str("HGROW $3\n").unwrap();
+        assert_eq!(program.to_bytes().unwrap(), vec![36, 3]);
+
+        let vm = assemble_and_run("LOAD $0 8\nHGROW $0\nLOAD $0 3\nHGROW $0\nHLT\n").unwrap();
+        assert_eq!(vm.heap, vec![0; 3]);
+        assert_eq!(vm.peak_heap(), 8);
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let program = Program::from_str("PUSH $1\nPOP $2\n").unwrap();
+        assert_eq!(program.to_bytes().unwrap(), vec![38, 1, 39, 2]);
+
+        let vm = assemble_and_run("LOAD $0 3\nPUSH $0\nLOAD $0 4\nPUSH $0\nPOP $1\nHLT\n").unwrap();
+        assert_eq!(vm.registers[1], 4);
+        assert_eq!(vm.stack, vec![3]);
+    }
+
+    #[test]
+    fn test_isqrt() {
+        let program = Program::from_str("ISQRT $1 $0\n").unwrap();
+        assert_eq!(program.to_bytes().unwrap(), vec![40, 1, 0]);
+
+        let vm = assemble_and_run("LOAD $0 99\nISQRT $1 $0\nHLT\n").unwrap();
+        assert_eq!(vm.registers[1], 9);
+    }
+
+    #[test]
+    fn test_store() {
+        let program = Program::from_str("STORE $0 $1\n").unwrap();
+        assert_eq!(program.to_bytes().unwrap(), vec![41, 0, 1]);
+
+        let source = "LOAD $0 4\nALLOC $0\nLOAD $1 2\nLOAD $2 300\nSTORE $1 $2\nLOADB $3 $1\nHLT\n";
+        let vm = assemble_and_run(source).unwrap();
+        assert_eq!(vm.heap, vec![0, 0, 44, 0]);
+        assert_eq!(vm.registers[3], 44);
+    }
+
+    #[test]
+    fn test_nop() {
+        let program = Program::from_str("NOP\nstart:\nJMP start\n").unwrap();
+        assert_eq!(
+            program.to_bytes().unwrap(),
+            vec![42, 0, 0, 0, 6, IMMEDIATE_TARGET, 0, 4]
+        );
+        assert_eq!(
+            Program::from_bytes(&program.to_bytes().unwrap()).unwrap(),
+            program
+        );
+    }
+
+    #[test]
+    fn test_move() {
+        let program = Program::from_str("MOVE $1 $0\n").unwrap();
+        assert_eq!(program.to_bytes().unwrap(), vec![43, 1, 0]);
+
+        let vm = assemble_and_run("LOAD $0 -12\nMOVE $1 $0\nHLT\n").unwrap();
+        assert_eq!(&vm.registers[..2], &[-12, -12]);
+    }
+
+    #[test]
+    fn test_mod() {
+        let program = Program::from_str("MOD $0 $1 $2\n").unwrap();
+        assert_eq!(program.to_bytes().unwrap(), vec![16, 0, 1, 2]);
+
+        let vm = assemble_and_run("LOAD $0 17\nLOAD $1 5\nMOD $0 $1 $2\nHLT\n").unwrap();
+        assert_eq!(vm.registers[2], 2);
+    }
+
+    #[test]
+    fn test_const() {
+        let vm = assemble_and_run(
+            ".const STACK_SIZE 256\nLOAD $0 STACK_SIZE\nLOAD $1 STACK_SIZE-LATE\nHLT\n.const LATE (2+3)\n",
+        )
+        .unwrap();
+        assert_eq!(vm.registers[..2], [256, 251]);
+
+        assert_eq!(
+            Program::from_str(".const N 1\n.const N 2\n"),
+            Err(AssembleError::DuplicateLabel("N".to_string(), 2))
+        );
+        assert_eq!(
+            Program::from_str("N:\n.const N 2\n"),
+            Err(AssembleError::DuplicateLabel("N".to_string(), 2))
+        );
+        assert!(Program::from_str(".const N here\nhere:\n").is_err());
+    }
+
+    #[test]
+    fn test_wrong_section() {
+        assert_eq!(
+            Program::from_str(".string \"hi\"\n"),
+            Err(AssembleError::WrongSection(1))
+        );
+        assert_eq!(
+            Program::from_str(".data\nHLT\n"),
+            Err(AssembleError::WrongSection(2))
+        );
+    }
 }
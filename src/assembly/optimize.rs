@@ -0,0 +1,177 @@
+//! Conservative optimizations of assembled programs
+use super::{Instruction, Program, Token};
+use crate::instruction::Opcode;
+
+impl Program {
+    /// Replaces `LOAD a x`, `LOAD b y`, `ADD a b d` style sequences by a single `LOAD d` of the
+    /// result
+    ///
+    /// A sequence is only folded when nothing jumps into its middle and both loaded registers
+    /// are `d` or overwritten before they are read again, so the program leaves the registers
+    /// exactly as before. Programs jumping by address are left alone, as folding moves code.
+    pub fn fold_constants(&mut self) {
+        if self.instructions.iter().any(jumps_by_address) {
+            return;
+        }
+
+        // Labels are tracked as instruction indices while instructions are removed
+        let mut labels: Vec<(String, usize)> = self
+            .symbols
+            .iter()
+            .map(|(name, offset)| (name.clone(), self.index_at(*offset)))
+            .collect();
+
+        let mut i = 0;
+        while i + 2 < self.instructions.len() {
+            let jumped_into = labels
+                .iter()
+                .any(|(_, index)| *index == i + 1 || *index == i + 2);
+            let folded = match fold(&self.instructions[i..]) {
+                Some((dst, value, loaded)) if !jumped_into => loaded
+                    .iter()
+                    .all(|&reg| reg == dst || is_overwritten(&self.instructions[i + 3..], reg))
+                    .then_some((dst, value)),
+                _ => None,
+            };
+
+            if let Some((dst, value)) = folded {
+                let load = Instruction {
+                    opcode: Opcode::Load,
+                    operands: vec![Token::Register { reg: dst }, Token::Number { num: value }],
+                };
+                self.instructions.splice(i..i + 3, [load]);
+                for (_, index) in labels.iter_mut().filter(|(_, index)| *index > i) {
+                    *index -= 2;
+                }
+            }
+            i += 1;
+        }
+
+        self.symbols = labels
+            .into_iter()
+            .map(|(name, index)| {
+                (
+                    name,
+                    self.instructions[..index]
+                        .iter()
+                        .map(Instruction::size)
+                        .sum(),
+                )
+            })
+            .collect();
+    }
+
+    // Index of the instruction starting at byte `offset`, labels always fall on one
+    fn index_at(&self, offset: usize) -> usize {
+        let mut position = 0;
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            if position >= offset {
+                return index;
+            }
+            position += instruction.size();
+        }
+        self.instructions.len()
+    }
+}
+
+// Destination, value and the registers loaded by a foldable `LOAD`, `LOAD`, arithmetic sequence
+fn fold(instructions: &[Instruction]) -> Option<(u8, i32, [u8; 2])> {
+    let [first, second, operation, ..] = instructions else {
+        return None;
+    };
+    let (a, x) = immediate_load(first)?;
+    let (b, y) = immediate_load(second)?;
+    let [Token::Register { reg: lhs }, Token::Register { reg: rhs }, Token::Register { reg: dst }] =
+        operation.operands.as_slice()
+    else {
+        return None;
+    };
+
+    // The second load wins if both load the same register
+    let value = |reg: u8| match reg {
+        _ if reg == b => Some(y),
+        _ if reg == a => Some(x),
+        _ => None,
+    };
+    let (lhs, rhs) = (value(*lhs)?, value(*rhs)?);
+    // `DIV` is left alone as it also sets `rem`
+    let result = match operation.opcode {
+        Opcode::Add => lhs.checked_add(rhs),
+        Opcode::Sub => lhs.checked_sub(rhs),
+        Opcode::Mul => lhs.checked_mul(rhs),
+        _ => None,
+    }?;
+    // The folded value must still fit a `LOAD` immediate
+    i16::try_from(result).ok()?;
+
+    Some((*dst, result, [a, b]))
+}
+
+fn immediate_load(instruction: &Instruction) -> Option<(u8, i32)> {
+    match (instruction.opcode, instruction.operands.as_slice()) {
+        (Opcode::Load, [Token::Register { reg }, Token::Number { num }]) => Some((*reg, *num)),
+        _ => None,
+    }
+}
+
+fn jumps_by_address(instruction: &Instruction) -> bool {
+    match instruction.opcode {
+        Opcode::Jmpf | Opcode::Jmpb | Opcode::Switch => true,
+        Opcode::Jmp | Opcode::Jeq | Opcode::Call => {
+            !matches!(instruction.operands[0], Token::Label(_))
+        }
+        _ => false,
+    }
+}
+
+// Whether straight-line code from the start of `instructions` loads `reg` before using it
+fn is_overwritten(instructions: &[Instruction], reg: u8) -> bool {
+    for instruction in instructions {
+        if instruction.opcode.is_terminating()
+            || matches!(
+                instruction.opcode,
+                Opcode::Jmp | Opcode::Jeq | Opcode::Call | Opcode::Ret
+            )
+        {
+            return false;
+        }
+        if immediate_load(instruction).is_some_and(|(loaded, _)| loaded == reg) {
+            return true;
+        }
+        let uses = instruction.operands.iter().any(|operand| match operand {
+            Token::Register { reg: used } => *used == reg,
+            Token::RegisterList { regs } => regs.contains(&reg),
+            _ => false,
+        });
+        if uses {
+            return false;
+        }
+    }
+    // Registers are observable once the program ends
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Vm, VmExit};
+
+    #[test]
+    fn test_fold_constants() {
+        let source = "LOAD $0 2\nLOAD $1 3\nADD $0 $1 $0\nLOAD $1 7\nJMP end\nLOAD $4 9\nend:\n\
+                      LOAD $2 6\nLOAD $3 4\nSUB $2 $3 $5\nHLT\n";
+        let original = Program::from_str(source).unwrap();
+        let mut folded = Program::from_str(source).unwrap();
+        folded.fold_constants();
+
+        // Only the first sequence folds, `$2` and `$3` of the second are still read at the end
+        assert_eq!(folded.len(), original.len() - 2);
+        assert_eq!(folded.iter().next().unwrap().to_string(), "LOAD $0 5");
+
+        let mut original_vm = Vm::with_program(original.to_bytes().unwrap());
+        let mut folded_vm = Vm::with_program(folded.to_bytes().unwrap());
+        assert_eq!(original_vm.run(), VmExit::Halted);
+        assert_eq!(folded_vm.run(), VmExit::Halted);
+        assert_eq!(folded_vm.registers, original_vm.registers);
+    }
+}
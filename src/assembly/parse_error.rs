@@ -0,0 +1,210 @@
+//! Locating and classifying the reason a line of assembly does not parse
+use super::{parse_immediate, parse_opcode, parse_register, parse_register_list, parse_target};
+use crate::instruction::OperandType;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ParseErrorKind {
+    UnknownOpcode,
+    BadRegister,
+    /// A register past `$31`
+    RegisterOutOfRange,
+    /// A register list longer than its one byte count can hold
+    TooManyRegisters,
+    BadNumber,
+    /// Operands missing, or left over after the last one
+    WrongOperandCount,
+    /// A malformed directive or label
+    Syntax,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            ParseErrorKind::UnknownOpcode => "unknown opcode",
+            ParseErrorKind::BadRegister => "bad register",
+            ParseErrorKind::RegisterOutOfRange => "register out of range",
+            ParseErrorKind::TooManyRegisters => "too many registers",
+            ParseErrorKind::BadNumber => "bad number",
+            ParseErrorKind::WrongOperandCount => "wrong operand count",
+            ParseErrorKind::Syntax => "syntax error",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// A line that does not parse, with the 1-based line and column of the offending token
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub token: String,
+    pub kind: ParseErrorKind,
+    /// The whole line, for pointing at the column
+    pub text: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "line {}, column {}: {} `{}`",
+            self.line, self.column, self.kind, self.token
+        )?;
+        writeln!(f, "{}", self.text)?;
+        write!(f, "{:>width$}", "^", width = self.column)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// The token starting at `rest`, up to the next whitespace
+fn word(rest: &str) -> &str {
+    rest.split_whitespace().next().unwrap_or("")
+}
+
+// Whether the register token at the start of `operand` is malformed or just too high
+fn register_error(operand: &str) -> ParseErrorKind {
+    match word(operand).strip_prefix('$').map(str::parse::<u32>) {
+        Some(Ok(_)) => ParseErrorKind::RegisterOutOfRange,
+        _ => ParseErrorKind::BadRegister,
+    }
+}
+
+/// Works out why `text`, which `parse_statement` rejected, does not parse
+pub(super) fn diagnose(line: usize, text: &str) -> ParseError {
+    let error = |rest: &str, kind| ParseError {
+        line,
+        column: text.len() - rest.len() + 1,
+        token: word(rest).to_string(),
+        kind,
+        text: text.to_string(),
+    };
+
+    let rest = text.trim_start();
+    if rest.starts_with('.') || rest.trim_end().ends_with(':') {
+        return error(rest, ParseErrorKind::Syntax);
+    }
+    let Ok((mut rest, opcode)) = parse_opcode(rest) else {
+        return error(rest, ParseErrorKind::UnknownOpcode);
+    };
+
+    for operand_type in opcode.operands().operands {
+        let operand = rest.trim_start();
+        if operand.is_empty() {
+            return error(operand, ParseErrorKind::WrongOperandCount);
+        }
+        // An operand glued to the mnemonic or the previous operand is not an operand
+        if operand.len() == rest.len() {
+            return error(operand, ParseErrorKind::Syntax);
+        }
+        let parsed = match operand_type {
+            OperandType::Register => parse_register(operand),
+            OperandType::Number => parse_immediate(operand),
+            OperandType::Target => parse_target(operand),
+            OperandType::RegisterList => parse_register_list(operand),
+        };
+        rest = match parsed {
+            Ok((rest, _)) => rest,
+            Err(_) => {
+                let kind = match operand_type {
+                    // A list that starts with a valid register only fails for its length
+                    OperandType::RegisterList if parse_register(operand).is_ok() => {
+                        ParseErrorKind::TooManyRegisters
+                    }
+                    OperandType::Register | OperandType::RegisterList => register_error(operand),
+                    OperandType::Target if operand.starts_with('$') => register_error(operand),
+                    OperandType::Number | OperandType::Target => ParseErrorKind::BadNumber,
+                };
+                return error(operand, kind);
+            }
+        };
+    }
+
+    // Everything the opcode takes parsed, so whatever is left is one operand too many
+    let extra = rest.trim_start();
+    let ends_in_list = opcode.operands().operands.last() == Some(&OperandType::RegisterList);
+    let kind = if extra.is_empty() || extra.len() == rest.len() {
+        // e.g. a number running straight into letters
+        ParseErrorKind::Syntax
+    } else if ends_in_list && extra.starts_with('$') {
+        // A register list stops at the first register it rejects
+        register_error(extra)
+    } else {
+        ParseErrorKind::WrongOperandCount
+    };
+    error(extra, kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembly::{AssembleError, Program};
+
+    fn parse_error(source: &str) -> ParseError {
+        match Program::from_str(source) {
+            Err(AssembleError::Parse(e)) => e,
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_diagnose() {
+        let cases = [
+            ("FOO $0 1", 1, "FOO", ParseErrorKind::UnknownOpcode),
+            ("LOAD $x 1", 6, "$x", ParseErrorKind::BadRegister),
+            ("LOAD $ 5", 6, "$", ParseErrorKind::BadRegister),
+            ("INC $", 5, "$", ParseErrorKind::BadRegister),
+            ("LOAD $0 12abc", 9, "12abc", ParseErrorKind::BadNumber),
+            ("ADD $0 $1", 10, "", ParseErrorKind::WrongOperandCount),
+            ("INC $0 $1", 8, "$1", ParseErrorKind::WrongOperandCount),
+            ("JMP $300", 5, "$300", ParseErrorKind::RegisterOutOfRange),
+            ("INC $32", 5, "$32", ParseErrorKind::RegisterOutOfRange),
+            (
+                "SUMALL $0 $1 $40",
+                14,
+                "$40",
+                ParseErrorKind::RegisterOutOfRange,
+            ),
+            ("SUMALL $0 $1 $x", 14, "$x", ParseErrorKind::BadRegister),
+            (".bytes 1 x", 1, ".bytes", ParseErrorKind::Syntax),
+        ];
+        for (text, column, token, kind) in cases {
+            assert_eq!(
+                diagnose(1, text),
+                ParseError {
+                    line: 1,
+                    column,
+                    token: token.to_string(),
+                    kind,
+                    text: text.to_string(),
+                },
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let error = parse_error("LOAD $0 1\n\nLOAD $0 1 ; fine\nSUB $0 $q $2\n");
+        assert_eq!((error.line, error.column), (4, 8));
+        assert_eq!(error.kind, ParseErrorKind::BadRegister);
+
+        let lines = ["HLT", "BOGUS"].into_iter().map(String::from);
+        match Program::from_lines(lines) {
+            Err(AssembleError::Parse(e)) => {
+                assert_eq!((e.line, e.kind), (2, ParseErrorKind::UnknownOpcode))
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_error_display() {
+        assert_eq!(
+            parse_error("HLT\nLOAD $0 1x\n").to_string(),
+            "line 2, column 9: bad number `1x`\nLOAD $0 1x\n        ^"
+        );
+    }
+}
@@ -1,27 +1,96 @@
+/// Register byte that marks a jump target as an immediate address.
+///
+/// Jump targets are encoded as a register byte followed by a 16-bit address. A
+/// register form (`JMP $0`) leaves the address zeroed, while a label or address
+/// form (`JMP loop`, `JMP 12`) stores this marker in place of the register and the
+/// byte offset in the address.
+pub const IMMEDIATE_TARGET: u8 = u8::MAX;
+
+/// Width every instruction occupies under `Encoding::Padded`
+pub const PADDED_INSTRUCTION_SIZE: usize = 4;
+
+/// How instructions are laid out in bytecode
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Encoding {
+    /// Every instruction takes exactly as many bytes as its opcode and operands
+    #[default]
+    Packed,
+    /// Every instruction is zero-padded to `PADDED_INSTRUCTION_SIZE` bytes, which keeps
+    /// the `* 4` arithmetic of jumps to a register pointing at instruction boundaries
+    Padded,
+}
+
+impl Encoding {
+    /// Number of bytes an instruction of `size` unpadded bytes occupies
+    pub fn width(&self, size: usize) -> usize {
+        match self {
+            Encoding::Packed => size,
+            // Instructions with long register lists span several slots
+            Encoding::Padded => size.next_multiple_of(PADDED_INSTRUCTION_SIZE),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum OperandType {
     Register,
-    Number,
+    Number,       // A signed immediate
+    Target,       // A register, a label or an address, see `IMMEDIATE_TARGET`
+    RegisterList, // A count followed by that many registers, always the last operand
+}
+
+impl OperandType {
+    /// Number of bytes the operand occupies in bytecode
+    pub fn size(&self) -> usize {
+        match self {
+            OperandType::Register => 1,
+            OperandType::Number => 2,
+            OperandType::Target => 3,
+            // Only the count, the registers themselves are part of the instruction's variable size
+            OperandType::RegisterList => 1,
+        }
+    }
+}
+
+/// Size of the unpadded instruction at `offset` including any register list, or `None`
+/// if the opcode is illegal or the instruction is cut short before its register count
+pub fn instruction_size(bytes: &[u8], offset: usize) -> Option<usize> {
+    let opcode = Opcode::from(*bytes.get(offset)?);
+    if opcode == Opcode::Igl {
+        return None;
+    }
+
+    let mut size = opcode.size();
+    let mut cursor = offset + 1;
+    for operand in opcode.operands().operands {
+        if operand == OperandType::RegisterList {
+            size += *bytes.get(cursor)? as usize;
+        }
+        cursor += operand.size();
+    }
+    Some(size)
 }
 
 pub struct Operands {
     pub operands: Vec<OperandType>,
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Opcode {
     Hlt,  //
     Load, // LOAD [reg] [val] - Load a value into a register
-    //                     `val` is a i32 value, `LOAD $0 10` converts to `01 00 00 0A`
+    //                     `val` is a signed 16-bit value, `LOAD $0 10` converts to `01 00 00 0A`
+    //                     and is sign-extended, `01 00 FF FF` loads -1 rather than 65535
     Add, // ADD [reg1] [reg2] [reg3] - Add two registers and store in a third
     Sub, // SUB [reg1] [reg2] [reg3] - Subtract two registers and store in a third
     Mul, // MUL [reg1] [reg2] [reg3] - Multiply two registers and store in a third
     Div, // DIV [reg1] [reg2] [reg3] - Divide two registers and store in a third
     //                              `rem` register holds reminder
-    Jmp,  // JMP [reg] - Jump to an address stored in a register
-    Jmpf, // JMPF [reg] - Jump forward from the current address
-    Jmpb, // JMPB [reg] - Jump backwards from the current address
-
+    Mod,  // MOD [reg1] [reg2] [reg3] - Store the remainder of dividing two registers in a third
+    Jmp,  // JMP [reg|label] - Jump to an address stored in a register or to a label
+    Jmpf, // JMPF [reg] - Jump forward over the next `reg` instructions
+    Jmpb, // JMPB [reg] - Jump backwards over the `reg` instructions preceding this one
+    //                 `JMPB 1` lands on the previous instruction, `JMPB 0` repeats this one
     Eq, // EQ [reg1] [reg2] - Set a register to 1 if two other registers are equal, 0 otherwise
     //                      `cmp` register holds the result
     Neq, // NEQ [reg1] [reg2] - Set a register to 1 if two other registers are not equal, 0 otherwise
@@ -34,13 +103,42 @@ pub enum Opcode {
     //                      `cmp` register holds the result
     Ltq, // LTE [reg1] [reg2] - Set a register to 1 if one register is less than or equal to another, 0 otherwise
     //                      `cmp` register holds the result
-    Jeq, // JEQ [reg] - Jump to an address stored in a register if the `cmp` register is 1
+    Jeq, // JEQ [reg|label] - Jump to an address stored in a register or to a label if the `cmp` register is 1
 
     Alloc, // ALLOC [reg] - Allocate a number of bytes on the heap
     Inc,   // INC [reg] - Increment the value in a register
     Dec,   // DEC [reg] - Decrement the value in a register
 
-    Igl, // IGL - Illegal instruction
+    Popcnt,    // POPCNT [dst] [src] - Count the set bits of a register and store in another
+    Hltif,     // HLTIF - Halt if the `cmp` register is 1
+    Switch, // SWITCH [reg] [base_reg] - Jump to entry `reg` of a table of `JMP`s starting at address `base_reg`
+    Sumall, // SUMALL [dst] [reg...] - Sum any number of registers and store in `dst`
+    Clamp,  // CLAMP [val] [lo] [hi] - Clamp register `val` into the range `lo..=hi` in place
+    Loadm,  // LOADM [dst] [addr] - Load the heap byte at the address held in `addr` into `dst`
+    Print,  // PRINT [reg] - Write the value of a register and a new line to the VM output
+    Incif,  // INCIF [reg] - Increment the value in a register if the `cmp` register is 1
+    Notcmp, // NOTCMP - Invert the `cmp` register, 1 becomes 0 and 0 becomes 1
+    Mulh, // MULH [reg1] [reg2] [reg3] - Multiply two registers and store the high 32 bits of the 64-bit product in a third
+    Loadif, // LOADIF [reg] [val] - Load a value into a register if the `cmp` register is 1
+    Spaceship, // SPACESHIP [reg1] [reg2] [reg3] - Store -1, 0 or 1 in a third register as one register is less than, equal to or greater than another
+    Call,      // CALL [reg|label] - Jump like `JMP`, remembering the next instruction for `RET`
+    Ret,       // RET - Return to the instruction after the latest `CALL`
+    Shl, // SHL [reg1] [reg2] [reg3] - Shift a register left by the bits in another and store in a third
+    //                              amounts of 32 or more, or negative, shift every bit out
+    Shr, // SHR [reg1] [reg2] [reg3] - Arithmetic shift a register right by the bits in another and store in a third
+    //                              amounts of 32 or more, or negative, leave only the sign, 0 or -1
+    Hgrow, // HGROW [reg] - Resize the heap to exactly the number of bytes held in `reg`
+    //                  growth is zero-filled, shrinking drops the bytes past the new end
+    Bt, // BT [reg] [bit_reg] - Set the `cmp` register to bit `bit_reg` of `reg`
+    //                      only the low 5 bits of `bit_reg` count, so bit 32 is bit 0 again
+    Push,  // PUSH [reg] - Push the value of a register onto the stack
+    Pop,   // POP [reg] - Pop the top of the stack into a register
+    Isqrt, // ISQRT [dst] [src] - Store the square root of a register, rounded down, in another
+    Store, // STORE [addr] [val] - Store the low byte of `val` at the heap address held in `addr`
+    Nop,   // NOP - Do nothing, filling a whole 4-byte slot even when packed
+    //          the 3 bytes after the opcode are reserved and written as 0
+    Move, // MOVE [dst] [src] - Copy the value of a register into another
+    Igl,  // IGL - Illegal instruction
 }
 
 impl From<u8> for Opcode {
@@ -62,10 +160,34 @@ impl From<u8> for Opcode {
             13 => Opcode::Gtq,
             14 => Opcode::Ltq,
             15 => Opcode::Jeq,
-
+            16 => Opcode::Mod,
             17 => Opcode::Alloc,
             18 => Opcode::Inc,
             19 => Opcode::Dec,
+            20 => Opcode::Popcnt,
+            21 => Opcode::Hltif,
+            22 => Opcode::Switch,
+            23 => Opcode::Sumall,
+            24 => Opcode::Clamp,
+            25 => Opcode::Loadm,
+            26 => Opcode::Print,
+            27 => Opcode::Incif,
+            28 => Opcode::Notcmp,
+            29 => Opcode::Mulh,
+            30 => Opcode::Loadif,
+            31 => Opcode::Spaceship,
+            32 => Opcode::Call,
+            33 => Opcode::Ret,
+            34 => Opcode::Shl,
+            35 => Opcode::Shr,
+            36 => Opcode::Hgrow,
+            37 => Opcode::Bt,
+            38 => Opcode::Push,
+            39 => Opcode::Pop,
+            40 => Opcode::Isqrt,
+            41 => Opcode::Store,
+            42 => Opcode::Nop,
+            43 => Opcode::Move,
 
             _ => Opcode::Igl,
         }
@@ -91,15 +213,154 @@ impl From<Opcode> for u8 {
             Opcode::Gtq => 13,
             Opcode::Ltq => 14,
             Opcode::Jeq => 15,
+            Opcode::Mod => 16,
             Opcode::Alloc => 17,
             Opcode::Inc => 18,
             Opcode::Dec => 19,
+            Opcode::Popcnt => 20,
+            Opcode::Hltif => 21,
+            Opcode::Switch => 22,
+            Opcode::Sumall => 23,
+            Opcode::Clamp => 24,
+            Opcode::Loadm => 25,
+            Opcode::Print => 26,
+            Opcode::Incif => 27,
+            Opcode::Notcmp => 28,
+            Opcode::Mulh => 29,
+            Opcode::Loadif => 30,
+            Opcode::Spaceship => 31,
+            Opcode::Call => 32,
+            Opcode::Ret => 33,
+            Opcode::Shl => 34,
+            Opcode::Shr => 35,
+            Opcode::Hgrow => 36,
+            Opcode::Bt => 37,
+            Opcode::Push => 38,
+            Opcode::Pop => 39,
+            Opcode::Isqrt => 40,
+            Opcode::Store => 41,
+            Opcode::Nop => 42,
+            Opcode::Move => 43,
             Opcode::Igl => unreachable!(),
         }
     }
 }
 
 impl Opcode {
+    /// Every valid opcode in encoding order, i.e. all variants except `Igl`
+    pub fn all() -> &'static [Opcode] {
+        &[
+            Opcode::Hlt,
+            Opcode::Load,
+            Opcode::Add,
+            Opcode::Sub,
+            Opcode::Mul,
+            Opcode::Div,
+            Opcode::Jmp,
+            Opcode::Jmpf,
+            Opcode::Jmpb,
+            Opcode::Eq,
+            Opcode::Neq,
+            Opcode::Gt,
+            Opcode::Lt,
+            Opcode::Gtq,
+            Opcode::Ltq,
+            Opcode::Jeq,
+            Opcode::Mod,
+            Opcode::Alloc,
+            Opcode::Inc,
+            Opcode::Dec,
+            Opcode::Popcnt,
+            Opcode::Hltif,
+            Opcode::Switch,
+            Opcode::Sumall,
+            Opcode::Clamp,
+            Opcode::Loadm,
+            Opcode::Print,
+            Opcode::Incif,
+            Opcode::Notcmp,
+            Opcode::Mulh,
+            Opcode::Loadif,
+            Opcode::Spaceship,
+            Opcode::Call,
+            Opcode::Ret,
+            Opcode::Shl,
+            Opcode::Shr,
+            Opcode::Hgrow,
+            Opcode::Bt,
+            Opcode::Push,
+            Opcode::Pop,
+            Opcode::Isqrt,
+            Opcode::Store,
+            Opcode::Nop,
+            Opcode::Move,
+        ]
+    }
+
+    /// Whether executing the opcode can stop the program, `HLTIF` included
+    pub fn is_terminating(&self) -> bool {
+        matches!(self, Opcode::Hlt | Opcode::Hltif | Opcode::Igl)
+    }
+
+    /// Assembly name of the opcode
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Opcode::Hlt => "HLT",
+            Opcode::Load => "LOAD",
+            Opcode::Add => "ADD",
+            Opcode::Sub => "SUB",
+            Opcode::Mul => "MUL",
+            Opcode::Div => "DIV",
+            Opcode::Jmp => "JMP",
+            Opcode::Jmpf => "JMPF",
+            Opcode::Jmpb => "JMPB",
+            Opcode::Eq => "EQ",
+            Opcode::Neq => "NEQ",
+            Opcode::Gt => "GT",
+            Opcode::Lt => "LT",
+            Opcode::Gtq => "GTQ",
+            Opcode::Ltq => "LTQ",
+            Opcode::Jeq => "JEQ",
+            Opcode::Mod => "MOD",
+            Opcode::Alloc => "ALLOC",
+            Opcode::Inc => "INC",
+            Opcode::Dec => "DEC",
+            Opcode::Popcnt => "POPCNT",
+            Opcode::Hltif => "HLTIF",
+            Opcode::Switch => "SWITCH",
+            Opcode::Sumall => "SUMALL",
+            Opcode::Clamp => "CLAMP",
+            Opcode::Loadm => "LOADM",
+            Opcode::Print => "PRINT",
+            Opcode::Incif => "INCIF",
+            Opcode::Notcmp => "NOTCMP",
+            Opcode::Mulh => "MULH",
+            Opcode::Loadif => "LOADIF",
+            Opcode::Spaceship => "SPACESHIP",
+            Opcode::Call => "CALL",
+            Opcode::Ret => "RET",
+            Opcode::Shl => "SHL",
+            Opcode::Shr => "SHR",
+            Opcode::Hgrow => "HGROW",
+            Opcode::Bt => "BT",
+            Opcode::Push => "PUSH",
+            Opcode::Pop => "POP",
+            Opcode::Isqrt => "ISQRT",
+            Opcode::Store => "STORE",
+            Opcode::Nop => "NOP",
+            Opcode::Move => "MOVE",
+            Opcode::Igl => "IGL",
+        }
+    }
+
+    /// Number of bytes the instruction occupies under `encoding`
+    pub fn encoded_size(&self, encoding: Encoding) -> usize {
+        encoding.width(self.size())
+    }
+
+    /// Number of bytes the opcode and its operands occupy, without any padding
+    ///
+    /// Registers in a register list come on top of this, see `instruction_size`.
     pub fn size(&self) -> usize {
         match self {
             Opcode::Hlt => 1,
@@ -108,7 +369,7 @@ impl Opcode {
             Opcode::Sub => 4,
             Opcode::Mul => 4,
             Opcode::Div => 4,
-            Opcode::Jmp => 2,
+            Opcode::Jmp => 4,
             Opcode::Jmpf => 2,
             Opcode::Jmpb => 2,
             Opcode::Eq => 3,
@@ -117,18 +378,47 @@ impl Opcode {
             Opcode::Lt => 3,
             Opcode::Gtq => 3,
             Opcode::Ltq => 3,
-            Opcode::Jeq => 2,
+            Opcode::Jeq => 4,
+            Opcode::Mod => 4,
             Opcode::Alloc => 2,
             Opcode::Inc => 2,
             Opcode::Dec => 2,
+            Opcode::Popcnt => 3,
+            Opcode::Hltif => 1,
+            Opcode::Switch => 3,
+            Opcode::Sumall => 3,
+            Opcode::Clamp => 4,
+            Opcode::Loadm => 3,
+            Opcode::Print => 2,
+            Opcode::Incif => 2,
+            Opcode::Notcmp => 1,
+            Opcode::Mulh => 4,
+            Opcode::Loadif => 4,
+            Opcode::Spaceship => 4,
+            Opcode::Call => 4,
+            Opcode::Ret => 1,
+            Opcode::Shl => 4,
+            Opcode::Shr => 4,
+            Opcode::Hgrow => 2,
+            Opcode::Bt => 3,
+            Opcode::Push => 2,
+            Opcode::Pop => 2,
+            Opcode::Isqrt => 3,
+            Opcode::Store => 3,
+            Opcode::Nop => 4,
+            Opcode::Move => 3,
             Opcode::Igl => unreachable!(),
         }
     }
 
+    /// Operand types in the order they are written in assembly and encoded
+    ///
+    /// Three-register arithmetic reads its sources first and its destination last:
+    /// `SUB $0 $1 $2` stores `$0 - $1` in `$2`.
     pub fn operands(&self) -> Operands {
         match self {
             Opcode::Hlt => Operands { operands: vec![] },
-            Opcode::Load => Operands {
+            Opcode::Load | Opcode::Loadif => Operands {
                 operands: vec![OperandType::Register, OperandType::Number],
             },
             Opcode::Add => Operands {
@@ -152,7 +442,7 @@ impl Opcode {
                     OperandType::Register,
                 ],
             },
-            Opcode::Div => Operands {
+            Opcode::Div | Opcode::Mod | Opcode::Shl | Opcode::Shr => Operands {
                 operands: vec![
                     OperandType::Register,
                     OperandType::Register,
@@ -160,7 +450,7 @@ impl Opcode {
                 ],
             },
             Opcode::Jmp => Operands {
-                operands: vec![OperandType::Register],
+                operands: vec![OperandType::Target],
             },
             Opcode::Jmpf => Operands {
                 operands: vec![OperandType::Register],
@@ -168,7 +458,7 @@ impl Opcode {
             Opcode::Jmpb => Operands {
                 operands: vec![OperandType::Register],
             },
-            Opcode::Eq => Operands {
+            Opcode::Eq | Opcode::Bt => Operands {
                 operands: vec![OperandType::Register, OperandType::Register],
             },
             Opcode::Neq => Operands {
@@ -186,10 +476,11 @@ impl Opcode {
             Opcode::Ltq => Operands {
                 operands: vec![OperandType::Register, OperandType::Register],
             },
-            Opcode::Jeq => Operands {
-                operands: vec![OperandType::Register],
+            Opcode::Jeq | Opcode::Call => Operands {
+                operands: vec![OperandType::Target],
             },
-            Opcode::Alloc => Operands {
+            Opcode::Ret | Opcode::Nop => Operands { operands: vec![] },
+            Opcode::Alloc | Opcode::Hgrow | Opcode::Push | Opcode::Pop => Operands {
                 operands: vec![OperandType::Register],
             },
             Opcode::Inc => Operands {
@@ -198,6 +489,40 @@ impl Opcode {
             Opcode::Dec => Operands {
                 operands: vec![OperandType::Register],
             },
+            Opcode::Popcnt | Opcode::Isqrt | Opcode::Move => Operands {
+                operands: vec![OperandType::Register, OperandType::Register],
+            },
+            Opcode::Hltif => Operands { operands: vec![] },
+            Opcode::Notcmp => Operands { operands: vec![] },
+            Opcode::Switch => Operands {
+                operands: vec![OperandType::Register, OperandType::Register],
+            },
+            Opcode::Sumall => Operands {
+                operands: vec![OperandType::Register, OperandType::RegisterList],
+            },
+            Opcode::Incif => Operands {
+                operands: vec![OperandType::Register],
+            },
+            Opcode::Print => Operands {
+                operands: vec![OperandType::Register],
+            },
+            Opcode::Loadm | Opcode::Store => Operands {
+                operands: vec![OperandType::Register, OperandType::Register],
+            },
+            Opcode::Mulh | Opcode::Spaceship => Operands {
+                operands: vec![
+                    OperandType::Register,
+                    OperandType::Register,
+                    OperandType::Register,
+                ],
+            },
+            Opcode::Clamp => Operands {
+                operands: vec![
+                    OperandType::Register,
+                    OperandType::Register,
+                    OperandType::Register,
+                ],
+            },
             Opcode::Igl => unreachable!(),
         }
     }
@@ -223,4 +548,45 @@ mod tests {
         let instruction = Instruction::new(Opcode::Hlt);
         assert_eq!(instruction.opcode, Opcode::Hlt);
     }
+
+    #[test]
+    fn test_size_matches_operands() {
+        for byte in 0..=u8::MAX {
+            let opcode = Opcode::from(byte);
+            if opcode == Opcode::Igl {
+                continue;
+            }
+
+            let operands_size: usize = opcode.operands().operands.iter().map(|o| o.size()).sum();
+            // `NOP` is the one opcode with reserved bytes instead of operands
+            let reserved = if opcode == Opcode::Nop { 3 } else { 0 };
+            assert_eq!(opcode.size(), 1 + operands_size + reserved, "{:?}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_instruction_size() {
+        assert_eq!(instruction_size(&[2, 0, 1, 2], 0), Some(4));
+        assert_eq!(instruction_size(&[0, 23, 0, 3, 1, 2, 3], 1), Some(6));
+        assert_eq!(instruction_size(&[23, 0], 0), None);
+        assert_eq!(instruction_size(&[255], 0), None);
+    }
+
+    #[test]
+    fn test_all_opcodes() {
+        let valid: Vec<Opcode> = (0..=u8::MAX)
+            .map(Opcode::from)
+            .filter(|&opcode| opcode != Opcode::Igl)
+            .collect();
+
+        assert_eq!(Opcode::all(), valid.as_slice());
+    }
+
+    #[test]
+    fn test_is_terminating() {
+        assert!(Opcode::Hlt.is_terminating());
+        assert!(Opcode::Hltif.is_terminating());
+        assert!(Opcode::Igl.is_terminating());
+        assert!(!Opcode::Add.is_terminating());
+    }
 }
@@ -0,0 +1,4 @@
+pub mod assembly;
+pub mod instruction;
+pub mod repl;
+pub mod vm;
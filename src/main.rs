@@ -1,12 +1,38 @@
-mod assembly;
-mod instruction;
-mod repl;
-mod vm;
+use std::fmt::Display;
+use std::process;
+use untitled2::{assembly, vm};
+
+const USAGE: &str = "usage: vm run <file.asm> [--entry <label>]";
 
 fn main() {
-    let program = assembly::Program::from_file("program.asm").unwrap();
-    let mut vm = vm::Vm::new();
-    vm.program = program.to_bytes();
-    vm.run();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (path, entry) = match args.as_slice() {
+        [] => ("program.asm", None),
+        [command, path] if command == "run" => (path.as_str(), None),
+        [command, path, flag, label] if command == "run" && flag == "--entry" => {
+            (path.as_str(), Some(label.as_str()))
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let program = assembly::Program::from_file(path).unwrap_or_else(|e| fail(e));
+    let bytes = program.to_bytes().unwrap_or_else(|e| fail(e));
+    let mut vm = vm::Vm::with_program_and_data(bytes, program.data_image());
+    if let Some(label) = entry {
+        vm.pc = program
+            .label_address(label)
+            .unwrap_or_else(|| fail(format!("Undefined entry label: {}", label)));
+    }
+    if let vm::VmExit::Error(e) = vm.run() {
+        eprintln!("{}! Terminating!", e);
+    }
     println!("{:#?}", vm);
 }
+
+fn fail(error: impl Display) -> ! {
+    eprintln!("{}", error);
+    process::exit(1);
+}
@@ -1,11 +1,46 @@
-use crate::vm::Vm;
+use crate::assembly::{disassemble, DisassemblyOptions};
+use crate::instruction::{Opcode, OperandType};
+use crate::vm::{Vm, VmExit, REGISTER_COUNT};
+use std::fmt;
+use std::fmt::Write as _;
 use std::io;
 use std::io::Write;
 use std::num::ParseIntError;
 
+/// Instructions `.run` executes before giving up, so that infinite loops don't hang the REPL
+const DEFAULT_INSTRUCTION_LIMIT: usize = 10_000;
+
+/// A token of a hex string that is not a byte, with its 0-based index among the tokens
+#[derive(Debug, PartialEq)]
+pub struct HexError {
+    pub index: usize,
+    pub token: String,
+    pub error: ParseIntError,
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid hex byte `{}` at index {}: {}",
+            self.token, self.index, self.error
+        )
+    }
+}
+
+impl std::error::Error for HexError {}
+
 pub struct Repl {
+    /// Every command entered so far, oldest first
     command_buffer: Vec<String>,
     vm: Vm,
+    instruction_limit: usize,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Repl {
@@ -14,20 +49,25 @@ impl Repl {
         Repl {
             vm: Vm::new(),
             command_buffer: vec![],
+            instruction_limit: DEFAULT_INSTRUCTION_LIMIT,
         }
     }
 
-    fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, ParseIntError> {
+    fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, HexError> {
         let split = i.split(' ').collect::<Vec<&str>>();
         let mut results: Vec<u8> = vec![];
-        for hex_string in split {
+        for (index, hex_string) in split.into_iter().enumerate() {
             let byte = u8::from_str_radix(hex_string, 16);
             match byte {
                 Ok(result) => {
                     results.push(result);
                 }
-                Err(e) => {
-                    return Err(e);
+                Err(error) => {
+                    return Err(HexError {
+                        index,
+                        token: hex_string.to_string(),
+                        error,
+                    });
                 }
             }
         }
@@ -53,37 +93,278 @@ impl Repl {
                 .read_line(&mut buffer)
                 .expect("Unable to read line from user");
             let buffer = buffer.trim();
-            match buffer {
-                ".quit" => {
-                    std::process::exit(0);
+            if buffer == ".quit" {
+                std::process::exit(0);
+            }
+            print!("{}", self.execute_command(buffer));
+        }
+    }
+
+    /// Executes a single REPL command and returns the text to show the user
+    pub fn execute_command(&mut self, command: &str) -> String {
+        let mut output = String::new();
+        if !command.trim().is_empty() {
+            self.command_buffer.push(command.to_string());
+        }
+        match command.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            [".history"] => {
+                for (i, command) in self.command_buffer.iter().enumerate() {
+                    writeln!(output, "{}: {}", i + 1, command).unwrap();
+                }
+            }
+            [".program"] => {
+                writeln!(
+                    output,
+                    "Listing instructions currently in VM's program vector:"
+                )
+                .unwrap();
+                for instruction in self.vm.program() {
+                    writeln!(output, "{}", instruction).unwrap();
+                }
+                writeln!(output, "End of Program Listing").unwrap();
+            }
+            [".registers"] => {
+                writeln!(output, "Listing registers and all contents:").unwrap();
+                match self.vm.register_tags() {
+                    Some(tags) => {
+                        for (i, (value, tag)) in self.vm.registers.iter().zip(tags).enumerate() {
+                            writeln!(output, "${}: {} ({:?})", i, value, tag).unwrap();
+                        }
+                    }
+                    None => writeln!(output, "{:#?}", self.vm.registers).unwrap(),
                 }
-                ".program" => {
-                    println!("Listing instructions currently in VM's program vector:");
-                    for instruction in &self.vm.program {
-                        println!("{}", instruction);
+                writeln!(output, "End of Register Listing").unwrap();
+            }
+            [".help"] => {
+                writeln!(output, "Supported instructions:").unwrap();
+                for opcode in Opcode::all() {
+                    write!(output, "{}", opcode.mnemonic()).unwrap();
+                    for operand in opcode.operands().operands {
+                        let operand = match operand {
+                            OperandType::Register => "$reg",
+                            OperandType::Number => "num",
+                            OperandType::Target => "$reg|label|address",
+                            OperandType::RegisterList => "$reg...",
+                        };
+                        write!(output, " {}", operand).unwrap();
                     }
-                    println!("End of Program Listing");
+                    writeln!(output).unwrap();
+                }
+            }
+            [".disasm", hex @ ..] => match self.parse_hex(&hex.join(" ")) {
+                Ok(bytes) => match disassemble(&bytes, &DisassemblyOptions::default()) {
+                    Ok(listing) => output.push_str(&listing),
+                    Err(e) => writeln!(output, "Unable to disassemble: {}", e).unwrap(),
+                },
+                Err(e) => writeln!(output, "Unable to decode hex string: {}", e).unwrap(),
+            },
+            [".watch", register] => match register
+                .strip_prefix('$')
+                .and_then(|register| register.parse::<usize>().ok())
+                .filter(|&register| register < REGISTER_COUNT)
+            {
+                Some(register) => {
+                    self.vm.watch_register(register);
+                    writeln!(output, "Watching ${}", register).unwrap();
                 }
-                ".registers" => {
-                    println!("Listing registers and all contents:");
-                    println!("{:#?}", self.vm.registers);
-                    println!("End of Register Listing")
+                None => writeln!(output, "Unable to parse register {}", register).unwrap(),
+            },
+            [".step"] => {
+                if self.vm.step().is_none() {
+                    writeln!(output, "End of program").unwrap();
                 }
-                _ => {
-                    let results = self.parse_hex(buffer);
-                    match results {
-                        Ok(bytes) => {
-                            for byte in bytes {
-                                self.vm.add_byte(byte)
-                            }
+                self.write_watch_events(&mut output);
+            }
+            [".tags"] => {
+                self.vm.enable_register_tags();
+                writeln!(output, "Register tagging enabled").unwrap();
+            }
+            [".run"] => {
+                self.vm.set_cycle_limit(Some(self.instruction_limit));
+                let exit = self.vm.run();
+                self.vm.set_cycle_limit(None);
+                match exit {
+                    VmExit::CycleLimit => writeln!(
+                        output,
+                        "Stopped after {} instructions, use .limit to raise the cap",
+                        self.instruction_limit
+                    )
+                    .unwrap(),
+                    VmExit::Error(e) => writeln!(output, "Error: {}", e).unwrap(),
+                    exit => writeln!(output, "Program stopped: {:?}", exit).unwrap(),
+                }
+                self.write_watch_events(&mut output);
+            }
+            [".limit", limit] => match limit.parse() {
+                Ok(limit) => {
+                    self.instruction_limit = limit;
+                    writeln!(output, "Instruction limit set to {}", limit).unwrap();
+                }
+                Err(_) => writeln!(output, "Unable to parse instruction limit {}", limit).unwrap(),
+            },
+            _ => {
+                let results = self.parse_hex(command);
+                match results {
+                    Ok(bytes) => {
+                        for byte in bytes {
+                            self.vm.add_byte(byte)
                         }
-                        Err(_e) => {
-                            println!("Unable to decode hex string. Please enter 4 groups of 2 hex characters.")
+                    }
+                    Err(e) => {
+                        writeln!(
+                            output,
+                            "Unable to decode hex string: {}. Please enter 4 groups of 2 hex characters.",
+                            e
+                        )
+                        .unwrap();
+                    }
+                };
+                match self.vm.pending_instruction_bytes() {
+                    Some((present, needed)) if present < needed => {
+                        writeln!(output, "Need {} more bytes", needed - present).unwrap();
+                    }
+                    _ => {
+                        if let Err(e) = self.vm.run_once() {
+                            writeln!(output, "Error: {}", e).unwrap();
                         }
-                    };
-                    self.vm.run_once();
+                    }
                 }
+                self.write_watch_events(&mut output);
             }
         }
+        output
+    }
+
+    fn write_watch_events(&mut self, output: &mut String) {
+        for event in self.vm.take_watch_events() {
+            writeln!(output, "{}", event).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_stops_at_limit() {
+        let mut repl = Repl::new();
+        repl.execute_command(".limit 50");
+        // JMP 0, an infinite loop
+        repl.vm.set_program(vec![6, 255, 0, 0]).unwrap();
+
+        let output = repl.execute_command(".run");
+        assert_eq!(
+            output,
+            "Stopped after 50 instructions, use .limit to raise the cap\n"
+        );
+        assert_eq!(repl.vm.pc, 0);
+    }
+
+    #[test]
+    fn test_help_lists_opcodes() {
+        let mut repl = Repl::new();
+        let output = repl.execute_command(".help");
+
+        assert_eq!(output.lines().count(), 1 + Opcode::all().len());
+        assert!(output.contains("\nLOAD $reg num\n"));
+        assert!(output.contains("\nSUMALL $reg $reg...\n"));
+    }
+
+    #[test]
+    fn test_disasm() {
+        let mut repl = Repl::new();
+
+        assert_eq!(
+            repl.execute_command(".disasm 01 00 01 F4"),
+            "0000: LOAD $0 500\n"
+        );
+        assert_eq!(
+            repl.execute_command(".disasm 01 00"),
+            "Unable to disassemble: Truncated Load instruction at 0\n"
+        );
+        assert!(repl.vm.program().is_empty());
+    }
+
+    #[test]
+    fn test_bad_hex_token() {
+        let mut repl = Repl::new();
+
+        let error = repl.parse_hex("01 00 ZZ F4").unwrap_err();
+        assert_eq!((error.index, error.token.as_str()), (2, "ZZ"));
+        assert_eq!(
+            repl.execute_command(".disasm 01 00 ZZ F4"),
+            "Unable to decode hex string: Invalid hex byte `ZZ` at index 2: invalid digit found in string\n"
+        );
+        assert!(repl
+            .execute_command("01 G0 00 05")
+            .starts_with("Unable to decode hex string: Invalid hex byte `G0` at index 1"));
+    }
+
+    #[test]
+    fn test_watch() {
+        let mut repl = Repl::new();
+        repl.vm
+            .set_program(vec![
+                1, 0, 0, 5, // LOAD 5 to register 0
+                1, 1, 0, 6, // LOAD 6 to register 1
+                19, 0, // DEC register 0
+            ])
+            .unwrap();
+
+        assert_eq!(repl.execute_command(".watch $0"), "Watching $0\n");
+        assert_eq!(
+            repl.execute_command(".step"),
+            "0000: $0 changed from 0 to 5\n"
+        );
+        assert_eq!(repl.execute_command(".step"), "");
+        assert_eq!(
+            repl.execute_command(".run"),
+            "Program stopped: EndOfProgram\n0008: $0 changed from 5 to 4\n"
+        );
+        assert_eq!(
+            repl.execute_command(".watch $32"),
+            "Unable to parse register $32\n"
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        let mut repl = Repl::new();
+        repl.execute_command(".limit 10");
+        repl.execute_command("");
+        repl.execute_command("01 00 00 05");
+
+        assert_eq!(repl.command_buffer, vec![".limit 10", "01 00 00 05"]);
+        assert_eq!(
+            repl.execute_command(".history"),
+            "1: .limit 10\n2: 01 00 00 05\n3: .history\n"
+        );
+    }
+
+    #[test]
+    fn test_partial_instruction() {
+        let mut repl = Repl::new();
+
+        assert_eq!(repl.execute_command("01 00"), "Need 2 more bytes\n");
+        assert_eq!(repl.vm.pc, 0);
+        assert_eq!(repl.execute_command("00 05"), "");
+        assert_eq!(repl.vm.registers[0], 5);
+    }
+
+    #[test]
+    fn test_instruction_error() {
+        let mut repl = Repl::new();
+
+        assert_eq!(
+            repl.execute_command("05 00 01 02"),
+            "Error: Division by zero at 0\n"
+        );
+
+        repl.vm.pc = 0;
+        assert_eq!(
+            repl.execute_command(".run"),
+            "Error: Division by zero at 0\n"
+        );
     }
 }
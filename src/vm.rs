@@ -1,508 +1,3410 @@
-use crate::instruction::Opcode;
+use crate::instruction::{instruction_size, Encoding, Opcode, OperandType, IMMEDIATE_TARGET};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
 
-const REGISTER_COUNT: usize = 32;
+/// Number of general purpose registers, `$0` to `$31`
+pub const REGISTER_COUNT: usize = 32;
+
+/// A single executed instruction as reported to the trace hook
+#[derive(Debug, PartialEq)]
+pub struct TraceStep {
+    pub pc: usize,
+    pub opcode: Opcode,
+    pub bytes: Vec<u8>,
+    pub changed_registers: Vec<(usize, i32)>,
+}
+
+impl fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}: {:?}", self.pc, self.opcode)?;
+        for byte in self.bytes.iter().skip(1) {
+            write!(f, " {:02X}", byte)?;
+        }
+        for (register, value) in &self.changed_registers {
+            write!(f, " | ${} = {}", register, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a single `Vm::step` changed, so a debugger UI only redraws what is needed
+#[derive(Debug, PartialEq)]
+pub struct StepDelta {
+    pub executed: Opcode,
+    pub pc: usize,
+    pub changed_registers: Vec<(usize, i32)>,
+    pub halted: bool,
+}
+
+/// A change to a register watched with `Vm::watch_register`
+#[derive(Debug, PartialEq, Clone)]
+pub struct WatchEvent {
+    /// Address of the instruction that wrote the register
+    pub pc: usize,
+    pub register: usize,
+    pub old: i32,
+    pub new: i32,
+}
+
+impl fmt::Display for WatchEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}: ${} changed from {} to {}",
+            self.pc, self.register, self.old, self.new
+        )
+    }
+}
+
+// Registers that differ between `before` and `after`, with their new value
+fn changed_registers(
+    before: &[i32; REGISTER_COUNT],
+    after: &[i32; REGISTER_COUNT],
+) -> Vec<(usize, i32)> {
+    (0..REGISTER_COUNT)
+        .filter(|&i| before[i] != after[i])
+        .map(|i| (i, after[i]))
+        .collect()
+}
+
+// Bit count for `SHL`/`SHR`, negative amounts count as too large so they shift every bit out
+fn shift_amount(value: i32) -> u32 {
+    u32::try_from(value).unwrap_or(u32::MAX)
+}
+
+// An operand read out of the bytecode, see `OperandType`
+#[derive(Debug, PartialEq, Clone)]
+enum Operand {
+    Register(u8),
+    Number(i32),
+    // An immediate jump target
+    Address(usize),
+    RegisterList(Vec<u8>),
+}
+
+// An instruction with all of its operands decoded, see `decode_operands`
+#[derive(Debug, PartialEq, Clone)]
+struct DecodedInstruction {
+    opcode: Opcode,
+    operands: Vec<Operand>,
+    // Bytes the instruction occupies without padding, register lists included
+    size: usize,
+}
+
+// The operand accessors panic on an operand of another type, which `decode_operands` rules out
+impl DecodedInstruction {
+    fn register(&self, index: usize) -> u8 {
+        match self.operands[index] {
+            Operand::Register(register) => register,
+            ref other => unreachable!("{:?} is not a register", other),
+        }
+    }
+
+    fn number(&self, index: usize) -> i32 {
+        match self.operands[index] {
+            Operand::Number(number) => number,
+            ref other => unreachable!("{:?} is not a number", other),
+        }
+    }
+
+    fn target(&self, index: usize) -> &Operand {
+        &self.operands[index]
+    }
+
+    fn register_list(&self, index: usize) -> &[u8] {
+        match &self.operands[index] {
+            Operand::RegisterList(registers) => registers,
+            other => unreachable!("{:?} is not a register list", other),
+        }
+    }
+}
+
+// What `Vm::compile` decoded, with the program it was decoded from to spot stale tables
+struct Compiled {
+    program: Vec<u8>,
+    // The instruction starting at each address, `None` inside an instruction
+    instructions: Vec<Option<DecodedInstruction>>,
+}
+
+// Reads operand bytes one after the other, failing where the program ends mid-operand
+struct OperandReader<'a> {
+    program: &'a [u8],
+    offset: usize,
+}
+
+impl OperandReader<'_> {
+    fn next_8_bits(&mut self) -> Result<u8, VmError> {
+        let result = *self
+            .program
+            .get(self.offset)
+            .ok_or(VmError::ProgramOutOfBounds { pc: self.offset })?;
+        self.offset += 1;
+        Ok(result)
+    }
+
+    fn next_16_bits(&mut self) -> Result<u16, VmError> {
+        let bytes = self
+            .program
+            .get(self.offset..self.offset + 2)
+            .ok_or(VmError::ProgramOutOfBounds { pc: self.offset })?;
+        self.offset += 2;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    // Reads a big-endian 32-bit immediate, most significant byte first like `next_16_bits`
+    #[allow(dead_code)] // No opcode takes a 32-bit immediate yet
+    fn next_32_bits(&mut self) -> Result<u32, VmError> {
+        let bytes = self
+            .program
+            .get(self.offset..self.offset + 4)
+            .ok_or(VmError::ProgramOutOfBounds { pc: self.offset })?;
+        self.offset += 4;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+// Reads the operands of the `opcode` instruction at `pc` in the order `Opcode::operands` lists
+// them, so that executing it never has to go back to the bytecode
+fn decode_operands(
+    program: &[u8],
+    pc: usize,
+    opcode: Opcode,
+) -> Result<DecodedInstruction, VmError> {
+    if opcode == Opcode::Igl {
+        // `Opcode::Igl` does not keep the byte, so it is read back from the program
+        return Err(VmError::IllegalOpcode {
+            pc,
+            byte: program[pc],
+        });
+    }
+
+    let mut reader = OperandReader {
+        program,
+        offset: pc + 1,
+    };
+    let mut operands = vec![];
+    let mut listed = 0;
+    for operand_type in opcode.operands().operands {
+        operands.push(match operand_type {
+            OperandType::Register => Operand::Register(reader.next_8_bits()?),
+            OperandType::Number => Operand::Number(reader.next_16_bits()? as i16 as i32),
+            OperandType::Target => {
+                let register = reader.next_8_bits()?;
+                let address = reader.next_16_bits()?;
+                if register == IMMEDIATE_TARGET {
+                    Operand::Address(address as usize)
+                } else {
+                    Operand::Register(register)
+                }
+            }
+            OperandType::RegisterList => {
+                let count = reader.next_8_bits()?;
+                let registers = (0..count)
+                    .map(|_| reader.next_8_bits())
+                    .collect::<Result<Vec<_>, _>>()?;
+                listed = registers.len();
+                Operand::RegisterList(registers)
+            }
+        });
+    }
+
+    // Opcodes such as `NOP` reserve bytes no operand covers, they must be there all the same
+    let size = opcode.size() + listed;
+    while reader.offset < pc + size {
+        reader.next_8_bits()?;
+    }
+
+    Ok(DecodedInstruction {
+        opcode,
+        operands,
+        size,
+    })
+}
+
+/// Why a program stopped running
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmExit {
+    Halted,
+    EndOfProgram,
+    CycleLimit,
+    Error(VmError),
+}
+
+impl VmExit {
+    /// The error the program stopped on, if any, for callers that only care whether it failed
+    pub fn into_result(self) -> Result<(), VmError> {
+        match self {
+            VmExit::Error(e) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How a run stopped along with the state it left behind, see `Vm::run_with_state`
+#[derive(Debug, PartialEq, Clone)]
+pub struct FinalState {
+    pub exit: VmExit,
+    pub pc: usize,
+    pub registers: [i32; REGISTER_COUNT],
+    pub rem: u32,
+    pub cmp: u32,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmError {
+    RegisterOutOfBounds { pc: usize, register: u8 },
+    IllegalOpcode { pc: usize, byte: u8 },
+    JumpOutOfBounds { pc: usize, target: i64 },
+    DivisionByZero { pc: usize },
+    ArithmeticOverflow { pc: usize, opcode: Opcode },
+    InvalidRange { pc: usize, lo: i32, hi: i32 },
+    HeapOutOfBounds { pc: usize, address: i32 },
+    ProgramOutOfBounds { pc: usize },
+    RelativeJumpOutOfBounds { pc: usize, count: i32 },
+    AllocTooLarge { pc: usize, size: i32 },
+    OutOfMemory { pc: usize, size: i32 },
+    ReturnWithoutCall { pc: usize },
+    StackUnderflow { pc: usize },
+    NegativeSquareRoot { pc: usize, value: i32 },
+    MissingHalt { pc: usize },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::IllegalOpcode { pc, byte } => {
+                write!(f, "Unrecognized opcode {:#04X} found at {}", byte, pc)
+            }
+            VmError::JumpOutOfBounds { pc, target } => {
+                write!(f, "Jump to {} out of bounds at {}", target, pc)
+            }
+            VmError::RegisterOutOfBounds { pc, register } => {
+                write!(f, "Register ${} out of bounds at {}", register, pc)
+            }
+            VmError::DivisionByZero { pc } => write!(f, "Division by zero at {}", pc),
+            VmError::ArithmeticOverflow { pc, opcode } => {
+                write!(f, "Arithmetic overflow in {:?} at {}", opcode, pc)
+            }
+            VmError::InvalidRange { pc, lo, hi } => {
+                write!(f, "Invalid range {}..={} at {}", lo, hi, pc)
+            }
+            VmError::HeapOutOfBounds { pc, address } => {
+                write!(f, "Heap address {} out of bounds at {}", address, pc)
+            }
+            VmError::ProgramOutOfBounds { pc } => {
+                write!(f, "Program ended in the middle of an operand at {}", pc)
+            }
+            VmError::AllocTooLarge { pc, size } => {
+                write!(
+                    f,
+                    "Allocation of {} bytes exceeds the heap limit at {}",
+                    size, pc
+                )
+            }
+            VmError::OutOfMemory { pc, size } => {
+                write!(f, "Out of memory allocating {} bytes at {}", size, pc)
+            }
+            VmError::ReturnWithoutCall { pc } => {
+                write!(f, "RET without a matching CALL at {}", pc)
+            }
+            VmError::StackUnderflow { pc } => {
+                write!(f, "POP from an empty stack at {}", pc)
+            }
+            VmError::NegativeSquareRoot { pc, value } => {
+                write!(f, "Square root of negative {} at {}", value, pc)
+            }
+            VmError::MissingHalt { pc } => {
+                write!(f, "Program ran off its end at {} without HLT", pc)
+            }
+            VmError::RelativeJumpOutOfBounds { pc, count } => {
+                write!(
+                    f,
+                    "Relative jump over {} instructions out of bounds at {}",
+                    count, pc
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// Error to force on the next instruction, see `Vm::inject_fault`
+#[cfg(debug_assertions)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FaultKind {
+    Overflow,
+    DivisionByZero,
+    BadRegister,
+    /// Fails the next `ALLOC` or `HGROW` as if the host refused the memory
+    OutOfMemory,
+}
+
+/// What a register is believed to hold, tracked for debugging only
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RegTag {
+    Unknown,
+    Value,
+    Address,
+}
+
+/// Opcodes a VM executed, see `Vm::enable_coverage`
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Coverage {
+    pub executed: HashSet<Opcode>,
+}
+
+impl Coverage {
+    /// Adds what `other` executed, e.g. to sum up the coverage of several runs
+    pub fn merge(&mut self, other: &Coverage) {
+        self.executed.extend(&other.executed);
+    }
+
+    /// Valid opcodes that were never executed, in encoding order
+    pub fn uncovered(&self) -> Vec<Opcode> {
+        Opcode::all()
+            .iter()
+            .filter(|opcode| !self.executed.contains(opcode))
+            .copied()
+            .collect()
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let uncovered = self.uncovered();
+        write!(
+            f,
+            "Executed {} of {} opcodes",
+            Opcode::all().len() - uncovered.len(),
+            Opcode::all().len()
+        )?;
+        if !uncovered.is_empty() {
+            let mnemonics: Vec<_> = uncovered.iter().map(Opcode::mnemonic).collect();
+            write!(f, ", never executed: {}", mnemonics.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// How `DIV` rounds quotients that are not whole, see `Vm::set_div_mode`
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum DivMode {
+    /// Towards zero like Rust's `/`, the remainder takes the sign of the dividend
+    #[default]
+    Trunc,
+    /// Towards negative infinity, the remainder takes the sign of the divisor
+    Floor,
+}
+
+/// What arithmetic opcodes such as `ADD`, `INC` and `DIV` do with a result that does not fit
+/// in a register
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OverflowMode {
+    /// Stop with `VmError::ArithmeticOverflow`
+    #[default]
+    Error,
+    /// Keep the low 32 bits, like two's complement hardware
+    Wrap,
+}
+
+/// Runtime policy of a VM, all in one place for `Vm::from_config`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct VmConfig {
+    /// Registers programs may use, `$0` up to but excluding this, at most `REGISTER_COUNT`
+    pub register_count: usize,
+    /// See `Vm::set_heap_limit`
+    pub max_heap: Option<usize>,
+    /// See `Vm::set_cycle_limit`
+    pub max_cycles: Option<usize>,
+    pub overflow_mode: OverflowMode,
+    /// See `Vm::set_div_mode`
+    pub div_mode: DivMode,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            register_count: REGISTER_COUNT,
+            max_heap: None,
+            max_cycles: None,
+            overflow_mode: OverflowMode::Error,
+            div_mode: DivMode::Trunc,
+        }
+    }
+}
+
+pub type TraceHook = Box<dyn FnMut(&TraceStep)>;
+
+pub type HaltHook = Box<dyn FnOnce(&Vm)>;
+
+// Output sink that stays readable after being handed to the VM, see `Vm::run_capturing`
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
 
-#[derive(Debug)]
 pub struct Vm {
     pub registers: [i32; REGISTER_COUNT],
     pub pc: usize,
+    #[deprecated(note = "use `program` and `set_program`")]
     pub program: Vec<u8>,
     pub heap: Vec<u8>,
+    /// Values moved here and back by `PUSH` and `POP`
+    pub stack: Vec<i32>,
 
     pub rem: u32,
     pub cmp: u32,
+
+    call_stack: Vec<usize>,
+    last_opcode: Option<Opcode>,
+    trace_hook: Option<TraceHook>,
+    watched: Vec<usize>,
+    watch_events: Vec<WatchEvent>,
+    on_halt: Option<HaltHook>,
+    output: Box<dyn Write>,
+    compiled: Option<Compiled>,
+    register_tags: Option<[RegTag; REGISTER_COUNT]>,
+    coverage: Option<Coverage>,
+    cycle_limit: Option<usize>,
+    strict_halt: bool,
+    register_count: usize,
+    overflow_mode: OverflowMode,
+    div_mode: DivMode,
+    heap_limit: Option<usize>,
+    peak_heap: usize,
+    encoding: Encoding,
+    #[cfg(debug_assertions)]
+    injected_fault: Option<FaultKind>,
+}
+
+impl fmt::Debug for Vm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Vm")
+            .field("registers", &self.registers)
+            .field("pc", &self.pc)
+            .field("program", &self.program())
+            .field("heap", &self.heap)
+            .field("stack", &self.stack)
+            .field("rem", &self.rem)
+            .field("cmp", &self.cmp)
+            .field("call_stack", &self.call_stack)
+            .field("last_opcode", &self.last_opcode)
+            .field("watched", &self.watched)
+            .field("register_tags", &self.register_tags)
+            .field("coverage", &self.coverage)
+            .field("cycle_limit", &self.cycle_limit)
+            .field("strict_halt", &self.strict_halt)
+            .field("register_count", &self.register_count)
+            .field("overflow_mode", &self.overflow_mode)
+            .field("div_mode", &self.div_mode)
+            .field("heap_limit", &self.heap_limit)
+            .field("peak_heap", &self.peak_heap)
+            .field("encoding", &self.encoding)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Vm {
     pub fn new() -> Vm {
+        Vm::from_config(VmConfig::default())
+    }
+
+    /// Creates a VM following the policy in `config`
+    ///
+    /// Panics if `config.register_count` is above `REGISTER_COUNT`.
+    #[allow(deprecated)]
+    pub fn from_config(config: VmConfig) -> Vm {
+        assert!(
+            config.register_count <= REGISTER_COUNT,
+            "at most {} registers",
+            REGISTER_COUNT
+        );
         Vm {
             registers: [0; REGISTER_COUNT],
             pc: 0,
             program: vec![],
             heap: vec![],
+            stack: vec![],
             rem: 0,
             cmp: 0,
+            call_stack: vec![],
+            last_opcode: None,
+            trace_hook: None,
+            watched: vec![],
+            watch_events: vec![],
+            on_halt: None,
+            output: Box::new(std::io::stdout()),
+            compiled: None,
+            register_tags: None,
+            coverage: None,
+            cycle_limit: config.max_cycles,
+            strict_halt: false,
+            register_count: config.register_count,
+            overflow_mode: config.overflow_mode,
+            div_mode: config.div_mode,
+            heap_limit: config.max_heap,
+            peak_heap: 0,
+            encoding: Encoding::Packed,
+            #[cfg(debug_assertions)]
+            injected_fault: None,
+        }
+    }
+
+    /// Creates a VM with `program` loaded
+    ///
+    /// Unlike `set_program` this does not check that the program ends on a whole instruction.
+    #[allow(deprecated)]
+    pub fn with_program(program: Vec<u8>) -> Vm {
+        Vm {
+            program,
+            ..Vm::new()
         }
     }
 
-    pub fn run(&mut self) {
-        let mut is_done = false;
-        while !is_done {
-            is_done = self.execute_instruction();
+    /// Creates a VM with `program` loaded and the heap starting out as `data`, e.g. the
+    /// `Program::data_image` the assembler laid out
+    pub fn with_program_and_data(program: Vec<u8>, data: Vec<u8>) -> Vm {
+        Vm {
+            peak_heap: data.len(),
+            heap: data,
+            ..Vm::with_program(program)
         }
     }
 
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    pub fn run(&mut self) -> VmExit {
+        let program = std::mem::take(self.program_mut());
+        let exit = self.execute_program(&program, None);
+        *self.program_mut() = program;
+        self.finish(&exit);
+        exit
+    }
+
+    /// Like `run`, but as a `Result` so that embedders can handle a failed program with `?`
+    ///
+    /// Halting, reaching the end of the program and hitting the cycle limit all count as `Ok`,
+    /// use `run` to tell them apart.
+    pub fn try_run(&mut self) -> Result<(), VmError> {
+        self.run().into_result()
     }
 
-    fn execute_instruction(&mut self) -> bool {
-        if self.pc >= self.program.len() {
-            return true;
+    /// Decodes the loaded program once so that `run_compiled` does not decode every
+    /// instruction and its operands again each time it is executed
+    ///
+    /// Fails like `set_program` if an instruction is illegal or cut short. Changing the
+    /// program discards what was compiled.
+    pub fn compile(&mut self) -> Result<(), VmError> {
+        let program = self.program().to_vec();
+        let mut instructions = vec![None; program.len()];
+        let mut address = 0;
+        while address < program.len() {
+            let instruction = decode_operands(&program, address, Opcode::from(program[address]))?;
+            let width = self.encoding.width(instruction.size);
+            instructions[address] = Some(instruction);
+            address += width;
         }
+        self.compiled = Some(Compiled {
+            program,
+            instructions,
+        });
+        Ok(())
+    }
 
-        let opcode = self.decode_opcode();
+    /// Like `run`, but executes the instructions decoded by the last `compile`
+    ///
+    /// Jumps that land inside an instruction, and programs that were never compiled or changed
+    /// since, are decoded as usual.
+    pub fn run_compiled(&mut self) -> VmExit {
+        let program = std::mem::take(self.program_mut());
+        let compiled = self.compiled.take();
+        // The deprecated `program` field can still be written without going through the VM
+        let instructions = compiled
+            .as_ref()
+            .filter(|compiled| compiled.program == program)
+            .map(|compiled| compiled.instructions.as_slice());
+        let exit = self.execute_program(&program, instructions);
+        self.compiled = compiled;
+        *self.program_mut() = program;
+        self.finish(&exit);
+        exit
+    }
 
-        match opcode {
-            Opcode::Hlt => {
-                return true;
-            }
-            Opcode::Load => {
-                let register = self.next_8_bits() as usize;
-                let number = self.next_16_bits() as i32;
-                self.registers[register] = number;
-            }
-            Opcode::Add => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
-            }
-            Opcode::Sub => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
-            }
-            Opcode::Mul => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
-            }
-            Opcode::Div => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
-                self.rem = (register1 % register2) as u32;
-            }
-            Opcode::Jmp => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.next_16_bits();
-                self.pc = target as usize * 4;
-            }
-            Opcode::Jmpb => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.next_16_bits();
-                self.pc -= target as usize * 4;
-            }
-            Opcode::Jmpf => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.next_16_bits();
-                self.pc += target as usize * 4;
-            }
-            Opcode::Eq => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.cmp = (register1 == register2) as u32;
-                self.next_8_bits();
-            }
-            Opcode::Neq => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.cmp = (register1 != register2) as u32;
-                self.next_8_bits();
-            }
-            Opcode::Gt => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.cmp = (register1 > register2) as u32;
-                self.next_8_bits();
-            }
-            Opcode::Lt => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.cmp = (register1 < register2) as u32;
-                self.next_8_bits();
+    /// Like `run`, but also returns a copy of the final `pc`, registers, `rem` and `cmp`
+    ///
+    /// The VM keeps its state, the copy only saves going back to it for assertions.
+    pub fn run_with_state(&mut self) -> FinalState {
+        let exit = self.run();
+        FinalState {
+            exit,
+            pc: self.pc,
+            registers: self.registers,
+            rem: self.rem,
+            cmp: self.cmp,
+        }
+    }
+
+    /// Runs the program up to `iterations` times, going back to where `pc` started after every
+    /// clean halt
+    ///
+    /// Registers carry over from one iteration to the next unless `reset_registers` is set.
+    /// Stops at the first iteration that ends any other way and returns how it ended.
+    pub fn run_loop(&mut self, iterations: usize, reset_registers: bool) -> VmExit {
+        let entry = self.pc;
+        let mut exit = VmExit::Halted;
+        for _ in 0..iterations {
+            self.pc = entry;
+            if reset_registers {
+                self.registers = [0; REGISTER_COUNT];
             }
-            Opcode::Gtq => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.cmp = (register1 >= register2) as u32;
-                self.next_8_bits();
+            exit = self.run();
+            if exit != VmExit::Halted {
+                break;
             }
-            Opcode::Ltq => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.cmp = (register1 <= register2) as u32;
-                self.next_8_bits();
+        }
+        exit
+    }
+
+    /// Executes the instruction at `pc`, returning the error it stopped on, if any
+    pub fn run_once(&mut self) -> Result<(), VmError> {
+        let program = std::mem::take(self.program_mut());
+        let exit = self.execute_instruction(&program, None);
+        *self.program_mut() = program;
+        match exit {
+            Some(exit) => {
+                self.finish(&exit);
+                exit.into_result()
             }
-            Opcode::Jeq => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.next_16_bits();
-                if self.cmp == 1 {
-                    self.pc = target as usize * 4;
-                }
+            None => Ok(()),
+        }
+    }
+
+    /// Executes a single instruction and reports what it changed
+    ///
+    /// Returns `None` without doing anything once `pc` is past the end of the program.
+    pub fn step(&mut self) -> Option<StepDelta> {
+        let pc = self.pc;
+        let executed = Opcode::from(*self.program().get(pc)?);
+        let registers = self.registers;
+
+        let program = std::mem::take(self.program_mut());
+        let exit = self.execute_instruction(&program, None);
+        *self.program_mut() = program;
+        if let Some(exit) = &exit {
+            self.finish(exit);
+        }
+
+        Some(StepDelta {
+            executed,
+            pc,
+            changed_registers: changed_registers(&registers, &self.registers),
+            halted: exit.is_some(),
+        })
+    }
+
+    /// Like `run_once`, but runs a `CALL` through to its `RET` instead of stepping into it
+    ///
+    /// Returns how the program stopped if it did, which may be inside the subroutine.
+    pub fn step_over(&mut self) -> Option<VmExit> {
+        let depth = self.call_stack.len();
+        let program = std::mem::take(self.program_mut());
+        let mut exit = self.execute_instruction(&program, None);
+        let mut cycles = 1;
+        while exit.is_none() && self.call_stack.len() > depth {
+            if self.cycle_limit.is_some_and(|limit| cycles >= limit) {
+                exit = Some(VmExit::CycleLimit);
+                break;
             }
-            Opcode::Alloc => {
-                let size = self.registers[self.next_8_bits() as usize];
-                let new_heap_len = self.heap.len() + size as usize;
-                self.heap.resize(new_heap_len, 0);
-                self.next_8_bits();
-                self.next_8_bits();
-            }
-            Opcode::Inc => {
-                let register = self.next_8_bits() as usize;
-                self.registers[register] += 1;
-                self.next_8_bits();
-                self.next_8_bits();
+            exit = self.execute_instruction(&program, None);
+            cycles += 1;
+        }
+        *self.program_mut() = program;
+        if let Some(exit) = &exit {
+            self.finish(exit);
+        }
+        exit
+    }
+
+    /// Number of `CALL`s still waiting for their `RET`
+    pub fn call_depth(&self) -> usize {
+        self.call_stack.len()
+    }
+
+    /// Runs the loaded program and returns everything it printed instead of writing it out
+    pub fn run_capturing(&mut self) -> (VmExit, String) {
+        let buffer = Rc::new(RefCell::new(vec![]));
+        let output = std::mem::replace(&mut self.output, Box::new(SharedBuffer(buffer.clone())));
+        let exit = self.run();
+        self.output = output;
+
+        let captured = String::from_utf8_lossy(&buffer.borrow()).into_owned();
+        (exit, captured)
+    }
+
+    /// Runs `program` from the current `pc` until it stops, leaving `self.program` untouched
+    pub fn execute(&mut self, program: &[u8]) -> VmExit {
+        let exit = self.execute_program(program, None);
+        self.finish(&exit);
+        exit
+    }
+
+    /// Installs a callback run once, with the final state, when the program halts
+    pub fn set_on_halt(&mut self, on_halt: HaltHook) {
+        self.on_halt = Some(on_halt);
+    }
+
+    // Called once the program stopped and `self` is back in a consistent state
+    fn finish(&mut self, exit: &VmExit) {
+        if *exit == VmExit::Halted {
+            if let Some(on_halt) = self.on_halt.take() {
+                on_halt(self);
             }
-            Opcode::Dec => {
-                let register = self.next_8_bits() as usize;
-                self.registers[register] -= 1;
-                self.next_8_bits();
-                self.next_8_bits();
+        }
+    }
+
+    // `compiled` optionally holds the decoded instruction starting at each address
+    fn execute_program(
+        &mut self,
+        program: &[u8],
+        compiled: Option<&[Option<DecodedInstruction>]>,
+    ) -> VmExit {
+        let mut cycles = 0;
+        loop {
+            if self.cycle_limit.is_some_and(|limit| cycles >= limit) {
+                return VmExit::CycleLimit;
             }
-            Opcode::Igl => {
-                println!("Unrecognized opcode found! Terminating!");
-                return true;
+            let decoded = compiled.and_then(|compiled| compiled.get(self.pc)?.as_ref());
+            if let Some(exit) = self.execute_instruction(program, decoded) {
+                return exit;
             }
+            cycles += 1;
         }
+    }
 
-        false
+    /// Makes the next executed instruction fail with `fault`, for testing error handling
+    ///
+    /// `FaultKind::OutOfMemory` waits for the next heap allocation instead.
+    #[cfg(debug_assertions)]
+    pub fn inject_fault(&mut self, fault: FaultKind) {
+        self.injected_fault = Some(fault);
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        self.pc += 1;
-        opcode
+    /// Redirects what `PRINT` writes, stdout by default
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
-        self.pc += 1;
-        result
+    /// Selects how the program bytes are laid out, `Encoding::Packed` by default
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+        // Instructions compiled for the other layout start at other addresses
+        self.compiled = None;
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
-        self.pc += 2;
-        result
+    /// Limits how many instructions a single `run`/`execute` may execute
+    pub fn set_cycle_limit(&mut self, limit: Option<usize>) {
+        self.cycle_limit = limit;
     }
 
-    pub fn add_byte(&mut self, byte: u8) {
-        self.program.push(byte);
+    /// Makes running off the end of the program an error rather than `VmExit::EndOfProgram`
+    ///
+    /// Catches truncated programs that lost their final `HLT`.
+    pub fn set_strict_halt(&mut self, strict: bool) {
+        self.strict_halt = strict;
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Selects how `DIV` rounds its quotient and remainder, `DivMode::Trunc` by default
+    pub fn set_div_mode(&mut self, mode: DivMode) {
+        self.div_mode = mode;
+    }
 
-    #[test]
-    fn test_create_vm() {
-        let vm = Vm::new();
-        assert_eq!(vm.registers[0], 0);
-        assert_eq!(vm.registers[REGISTER_COUNT - 1], 0);
+    /// The loaded program
+    #[allow(deprecated)]
+    pub fn program(&self) -> &[u8] {
+        &self.program
     }
 
-    #[test]
-    fn test_opcode_hlt() {
-        let mut vm = Vm::new();
-        vm.program = vec![0, 0, 0, 0];
-        vm.run();
+    // The one way the VM itself writes the deprecated field
+    #[allow(deprecated)]
+    fn program_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.program
+    }
 
-        assert_eq!(vm.pc, 1);
+    /// Loads `program` and starts over at its first instruction
+    ///
+    /// Fails if an instruction is illegal or cut short by the end of the program.
+    pub fn set_program(&mut self, program: Vec<u8>) -> Result<(), VmError> {
+        let mut pc = 0;
+        while pc < program.len() {
+            let size = instruction_size(&program, pc).ok_or(match Opcode::from(program[pc]) {
+                Opcode::Igl => VmError::IllegalOpcode {
+                    pc,
+                    byte: program[pc],
+                },
+                _ => VmError::ProgramOutOfBounds { pc },
+            })?;
+            if pc + size > program.len() {
+                return Err(VmError::ProgramOutOfBounds { pc });
+            }
+            pc += self.encoding.width(size);
+        }
+
+        *self.program_mut() = program;
+        self.pc = 0;
+        self.call_stack.clear();
+        self.compiled = None;
+        Ok(())
     }
 
-    #[test]
-    fn test_opcode_igl() {
-        let mut vm = Vm::new();
-        vm.program = vec![255, 0, 0, 0];
-        vm.run();
+    /// Caps the heap at `limit` bytes, `ALLOC`s growing it further fail
+    pub fn set_heap_limit(&mut self, limit: Option<usize>) {
+        self.heap_limit = limit;
+    }
 
-        assert_eq!(vm.pc, 1);
+    /// Largest size in bytes the heap has reached
+    pub fn peak_heap(&self) -> usize {
+        self.peak_heap
     }
 
-    #[test]
-    fn test_opcode_load() {
-        let mut vm = Vm::new();
-        vm.program = vec![1, 0, 1, 244];
-        vm.run_once();
+    /// Remainder of the last `DIV`, with the sign it was computed with
+    pub fn remainder(&self) -> i32 {
+        self.rem as i32
+    }
 
-        assert_eq!(vm.registers[0], 500);
+    /// Whether the last comparison held, i.e. the `cmp` register is 1
+    pub fn comparison_flag(&self) -> bool {
+        self.cmp == 1
     }
 
-    #[test]
-    fn test_opcode_add() {
-        let mut vm = Vm::new();
-        vm.program = vec![
-            1, 0, 0, 250, // LOAD 250 to register 0
-            1, 1, 0, 250, // LOAD 250 to register 1
-            2, 0, 1, 2,
-        ]; // ADD register 0 and register 1, store result in register 2
-        vm.run();
+    /// Non-zero registers with their index, in register order
+    pub fn used_registers(&self) -> Vec<(usize, i32)> {
+        changed_registers(&[0; REGISTER_COUNT], &self.registers)
+    }
 
-        assert_eq!(vm.registers[0], 250);
-        assert_eq!(vm.registers[1], 250);
-        assert_eq!(vm.registers[2], 500);
+    /// Bytes of the instruction at `pc` already in the program and the bytes it needs in total,
+    /// or `None` if there is nothing at `pc`
+    pub fn pending_instruction_bytes(&self) -> Option<(usize, usize)> {
+        let opcode = Opcode::from(*self.program().get(self.pc)?);
+        let needed = match opcode {
+            Opcode::Igl => 1,
+            // Until the count of a register list arrives only the fixed part is known
+            _ => instruction_size(self.program(), self.pc).unwrap_or(opcode.size()),
+        };
+        let present = (self.program().len() - self.pc).min(needed);
+        Some((present, needed))
+    }
+
+    /// Returns the opcode of the most recently executed instruction
+    pub fn last_opcode(&self) -> Option<Opcode> {
+        self.last_opcode
+    }
+
+    /// Starts tracking what registers hold, see `RegTag`
+    pub fn enable_register_tags(&mut self) {
+        if self.register_tags.is_none() {
+            self.register_tags = Some([RegTag::Unknown; REGISTER_COUNT]);
+        }
+    }
+
+    pub fn register_tags(&self) -> Option<&[RegTag; REGISTER_COUNT]> {
+        self.register_tags.as_ref()
+    }
+
+    /// Starts recording which opcodes get executed, see `Coverage`
+    pub fn enable_coverage(&mut self) {
+        if self.coverage.is_none() {
+            self.coverage = Some(Coverage::default());
+        }
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Installs a hook called after every executed instruction
+    pub fn set_trace_hook(&mut self, hook: TraceHook) {
+        self.trace_hook = Some(hook);
+    }
+
+    /// Records a `WatchEvent` every time an instruction changes `register`
+    ///
+    /// Panics if `register` is not below `REGISTER_COUNT`.
+    pub fn watch_register(&mut self, register: usize) {
+        assert!(register < REGISTER_COUNT, "no register ${}", register);
+        if !self.watched.contains(&register) {
+            self.watched.push(register);
+        }
+    }
+
+    /// Changes to watched registers since the last call, oldest first
+    pub fn take_watch_events(&mut self) -> Vec<WatchEvent> {
+        std::mem::take(&mut self.watch_events)
+    }
+
+    pub fn clear_trace_hook(&mut self) {
+        self.trace_hook = None;
+    }
+
+    /// Writes a line per executed instruction to `path`, see `TraceStep` for the format
+    pub fn trace_to_file(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut file = std::io::LineWriter::new(std::fs::File::create(path)?);
+        self.set_trace_hook(Box::new(move |step| {
+            // A failing trace must not abort the program being traced
+            let _ = writeln!(file, "{}", step);
+        }));
+        Ok(())
+    }
+
+    // Returns how the program stopped, or `None` if it can continue
+    //
+    // `decoded` is the instruction at `pc` if it is already known.
+    fn execute_instruction(
+        &mut self,
+        program: &[u8],
+        decoded: Option<&DecodedInstruction>,
+    ) -> Option<VmExit> {
+        if self.pc >= program.len() {
+            if self.strict_halt {
+                return Some(VmExit::Error(VmError::MissingHalt { pc: self.pc }));
+            }
+            return Some(VmExit::EndOfProgram);
+        }
+
+        if self.trace_hook.is_none() && self.watched.is_empty() {
+            return self.try_execute_instruction(program, decoded);
+        }
+
+        let pc = self.pc;
+        let registers = self.registers;
+        let exit = self.try_execute_instruction(program, decoded);
+
+        for &register in &self.watched {
+            if registers[register] != self.registers[register] {
+                self.watch_events.push(WatchEvent {
+                    pc,
+                    register,
+                    old: registers[register],
+                    new: self.registers[register],
+                });
+            }
+        }
+        if self.trace_hook.is_none() {
+            return exit;
+        }
+
+        let opcode = Opcode::from(program[pc]);
+        let width = instruction_size(program, pc).map_or(1, |size| self.encoding.width(size));
+        let end = (pc + width).min(program.len());
+        let step = TraceStep {
+            pc,
+            opcode,
+            bytes: program[pc..end].to_vec(),
+            changed_registers: changed_registers(&registers, &self.registers),
+        };
+        if let Some(hook) = self.trace_hook.as_mut() {
+            hook(&step);
+        }
+
+        exit
+    }
+
+    fn try_execute_instruction(
+        &mut self,
+        program: &[u8],
+        decoded: Option<&DecodedInstruction>,
+    ) -> Option<VmExit> {
+        match self.decode_and_execute(program, decoded) {
+            Ok(exit) => exit,
+            Err(e) => Some(VmExit::Error(e)),
+        }
+    }
+
+    fn decode_and_execute(
+        &mut self,
+        program: &[u8],
+        decoded: Option<&DecodedInstruction>,
+    ) -> Result<Option<VmExit>, VmError> {
+        let instruction_start = self.pc;
+        let opcode = decoded.map_or_else(
+            || Opcode::from(program[instruction_start]),
+            |instruction| instruction.opcode,
+        );
+        self.last_opcode = Some(opcode);
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.executed.insert(opcode);
+        }
+
+        #[cfg(debug_assertions)]
+        if let Some(fault) = self
+            .injected_fault
+            .take_if(|fault| *fault != FaultKind::OutOfMemory)
+        {
+            let pc = instruction_start;
+            return Err(match fault {
+                FaultKind::Overflow => VmError::ArithmeticOverflow { pc, opcode },
+                FaultKind::DivisionByZero => VmError::DivisionByZero { pc },
+                FaultKind::BadRegister => VmError::RegisterOutOfBounds {
+                    pc,
+                    register: REGISTER_COUNT as u8,
+                },
+                FaultKind::OutOfMemory => unreachable!(),
+            });
+        }
+
+        let decoded_here;
+        let instruction = match decoded {
+            Some(instruction) => instruction,
+            None => match decode_operands(program, instruction_start, opcode) {
+                Ok(instruction) => {
+                    decoded_here = instruction;
+                    &decoded_here
+                }
+                Err(e) => {
+                    // `pc` stops where decoding did: past an illegal opcode, or at the missing byte
+                    self.pc = match e {
+                        VmError::ProgramOutOfBounds { pc } => pc,
+                        _ => instruction_start + 1,
+                    };
+                    return Err(e);
+                }
+            },
+        };
+        self.execute_decoded(program, instruction)
+    }
+
+    // Executes `instruction`, which starts at `pc`, and moves `pc` on to the next one
+    //
+    // `pc` is only moved once the instruction succeeded, errors point at its start.
+    fn execute_decoded(
+        &mut self,
+        program: &[u8],
+        instruction: &DecodedInstruction,
+    ) -> Result<Option<VmExit>, VmError> {
+        let instruction_start = self.pc;
+        let opcode = instruction.opcode;
+        let next = instruction_start + self.encoding.width(instruction.size);
+        // Register operands by position, as `Opcode::operands` lists them
+        let register = |index| instruction.register(index);
+
+        let mut jump_to = None;
+        let mut halted = false;
+
+        match opcode {
+            Opcode::Hlt => {
+                halted = true;
+            }
+            Opcode::Nop => {}
+            Opcode::Load | Opcode::Loadif => {
+                let number = instruction.number(1);
+                if opcode == Opcode::Load || self.cmp == 1 {
+                    self.write_reg(register(0), number)?;
+                } else {
+                    // A skipped load still names a register that must exist
+                    self.read_reg(register(0))?;
+                }
+            }
+            Opcode::Add => {
+                let register1 = self.read_reg(register(0))?;
+                let register2 = self.read_reg(register(1))?;
+                let result = self.overflow(
+                    register1.overflowing_add(register2),
+                    instruction_start,
+                    opcode,
+                )?;
+                self.write_reg(register(2), result)?;
+            }
+            Opcode::Sub => {
+                let register1 = self.read_reg(register(0))?;
+                let register2 = self.read_reg(register(1))?;
+                let result = self.overflow(
+                    register1.overflowing_sub(register2),
+                    instruction_start,
+                    opcode,
+                )?;
+                self.write_reg(register(2), result)?;
+            }
+            Opcode::Mul => {
+                let register1 = self.read_reg(register(0))?;
+                let register2 = self.read_reg(register(1))?;
+                let result = self.overflow(
+                    register1.overflowing_mul(register2),
+                    instruction_start,
+                    opcode,
+                )?;
+                self.write_reg(register(2), result)?;
+            }
+            Opcode::Spaceship => {
+                let register1 = self.read_reg(register(0))?;
+                let register2 = self.read_reg(register(1))?;
+                self.write_reg(register(2), register1.cmp(&register2) as i32)?;
+            }
+            Opcode::Mulh => {
+                let register1 = self.read_reg(register(0))? as i64;
+                let register2 = self.read_reg(register(1))? as i64;
+                self.write_reg(register(2), ((register1 * register2) >> 32) as i32)?;
+            }
+            Opcode::Div => {
+                let register1 = self.read_reg(register(0))?;
+                let register2 = self.read_reg(register(1))?;
+                if register2 == 0 {
+                    return Err(VmError::DivisionByZero {
+                        pc: instruction_start,
+                    });
+                }
+                let (quotient, remainder) =
+                    self.divide(register1, register2, instruction_start, opcode)?;
+                self.write_reg(register(2), quotient)?;
+                self.rem = remainder as u32;
+            }
+            Opcode::Mod => {
+                let register1 = self.read_reg(register(0))?;
+                let register2 = self.read_reg(register(1))?;
+                if register2 == 0 {
+                    return Err(VmError::DivisionByZero {
+                        pc: instruction_start,
+                    });
+                }
+                let (_, remainder) =
+                    self.divide(register1, register2, instruction_start, opcode)?;
+                self.write_reg(register(2), remainder)?;
+            }
+            Opcode::Shl => {
+                let register1 = self.read_reg(register(0))?;
+                let amount = shift_amount(self.read_reg(register(1))?);
+                self.write_reg(register(2), register1.checked_shl(amount).unwrap_or(0))?;
+            }
+            Opcode::Shr => {
+                let register1 = self.read_reg(register(0))?;
+                let amount = shift_amount(self.read_reg(register(1))?);
+                let shifted = register1.checked_shr(amount).unwrap_or(register1 >> 31);
+                self.write_reg(register(2), shifted)?;
+            }
+            Opcode::Jmp => {
+                jump_to = Some(self.target(program, instruction.target(0))?);
+            }
+            Opcode::Jmpb => {
+                let count = self.read_reg(register(0))?;
+                let target = usize::try_from(count)
+                    .ok()
+                    .and_then(|count| self.instructions_back(program, instruction_start, count));
+                jump_to = Some(target.ok_or(VmError::RelativeJumpOutOfBounds {
+                    pc: instruction_start,
+                    count,
+                })?);
+            }
+            Opcode::Jmpf => {
+                let count = self.read_reg(register(0))?;
+                let target = usize::try_from(count)
+                    .ok()
+                    .and_then(|count| self.instructions_forward(program, next, count));
+                jump_to = Some(target.ok_or(VmError::RelativeJumpOutOfBounds {
+                    pc: instruction_start,
+                    count,
+                })?);
+            }
+            Opcode::Eq => {
+                let register1 = self.read_reg(register(0))?;
+                let register2 = self.read_reg(register(1))?;
+                self.cmp = (register1 == register2) as u32;
+            }
+            Opcode::Bt => {
+                let value = self.read_reg(register(0))?;
+                let bit = self.read_reg(register(1))? & 31;
+                self.cmp = (value >> bit) as u32 & 1;
+            }
+            Opcode::Neq => {
+                let register1 = self.read_reg(register(0))?;
+                let register2 = self.read_reg(register(1))?;
+                self.cmp = (register1 != register2) as u32;
+            }
+            Opcode::Gt => {
+                let register1 = self.read_reg(register(0))?;
+                let register2 = self.read_reg(register(1))?;
+                self.cmp = (register1 > register2) as u32;
+            }
+            Opcode::Lt => {
+                let register1 = self.read_reg(register(0))?;
+                let register2 = self.read_reg(register(1))?;
+                self.cmp = (register1 < register2) as u32;
+            }
+            Opcode::Gtq => {
+                let register1 = self.read_reg(register(0))?;
+                let register2 = self.read_reg(register(1))?;
+                self.cmp = (register1 >= register2) as u32;
+            }
+            Opcode::Ltq => {
+                let register1 = self.read_reg(register(0))?;
+                let register2 = self.read_reg(register(1))?;
+                self.cmp = (register1 <= register2) as u32;
+            }
+            Opcode::Call => {
+                let target = self.target(program, instruction.target(0))?;
+                self.call_stack.push(next);
+                jump_to = Some(target);
+            }
+            Opcode::Ret => {
+                let return_address = self.call_stack.pop().ok_or(VmError::ReturnWithoutCall {
+                    pc: instruction_start,
+                })?;
+                jump_to = Some(return_address);
+            }
+            Opcode::Push => {
+                let value = self.read_reg(register(0))?;
+                self.stack.push(value);
+            }
+            Opcode::Pop => {
+                // Checked first so a bad register does not lose the value
+                self.read_reg(register(0))?;
+                let value = self.stack.pop().ok_or(VmError::StackUnderflow {
+                    pc: instruction_start,
+                })?;
+                self.write_reg(register(0), value)?;
+            }
+            Opcode::Jeq => {
+                if self.cmp == 1 {
+                    jump_to = Some(self.target(program, instruction.target(0))?);
+                } else if let Operand::Register(target) = *instruction.target(0) {
+                    // A jump not taken still names a register that must exist
+                    self.read_reg(target)?;
+                }
+            }
+            Opcode::Alloc => {
+                let size = self.read_reg(register(0))?;
+                let address = self.heap.len();
+                let new_len = usize::try_from(size)
+                    .ok()
+                    .and_then(|size| address.checked_add(size))
+                    .filter(|&len| self.heap_limit.is_none_or(|limit| len <= limit))
+                    .ok_or(VmError::AllocTooLarge {
+                        pc: instruction_start,
+                        size,
+                    })?;
+                // The host refusing the memory is the program's error, not a reason to abort
+                self.reserve_heap(new_len - address, instruction_start, size)?;
+                self.heap.resize(new_len, 0);
+                self.peak_heap = self.peak_heap.max(new_len);
+                // The register keeps its size, the tag only marks what it was allocated for
+                self.write_reg_tagged(register(0), size, RegTag::Address)?;
+            }
+            Opcode::Hgrow => {
+                let size = self.read_reg(register(0))?;
+                let new_len = usize::try_from(size)
+                    .ok()
+                    .filter(|&len| self.heap_limit.is_none_or(|limit| len <= limit))
+                    .ok_or(VmError::AllocTooLarge {
+                        pc: instruction_start,
+                        size,
+                    })?;
+                if let Some(additional) = new_len.checked_sub(self.heap.len()) {
+                    self.reserve_heap(additional, instruction_start, size)?;
+                }
+                self.heap.resize(new_len, 0);
+                self.peak_heap = self.peak_heap.max(new_len);
+            }
+            Opcode::Inc | Opcode::Incif => {
+                let value = self.read_reg(register(0))?;
+                if opcode == Opcode::Inc || self.cmp == 1 {
+                    let result =
+                        self.overflow(value.overflowing_add(1), instruction_start, opcode)?;
+                    self.write_reg(register(0), result)?;
+                }
+            }
+            Opcode::Dec => {
+                let value = self.read_reg(register(0))?;
+                let result = self.overflow(value.overflowing_sub(1), instruction_start, opcode)?;
+                self.write_reg(register(0), result)?;
+            }
+            Opcode::Popcnt => {
+                let value = self.read_reg(register(1))?;
+                self.write_reg(register(0), value.count_ones() as i32)?;
+            }
+            Opcode::Move => {
+                let value = self.read_reg(register(1))?;
+                // A copied address is still an address
+                let tag = self.reg_tag(register(1));
+                self.write_reg_tagged(register(0), value, tag)?;
+            }
+            Opcode::Isqrt => {
+                let value = self.read_reg(register(1))?;
+                let root = value.checked_isqrt().ok_or(VmError::NegativeSquareRoot {
+                    pc: instruction_start,
+                    value,
+                })?;
+                self.write_reg(register(0), root)?;
+            }
+            Opcode::Notcmp => {
+                self.cmp = (self.cmp == 0) as u32;
+            }
+            Opcode::Hltif => {
+                halted = self.cmp == 1;
+            }
+            Opcode::Switch => {
+                let index = self.read_reg(register(0))? as i64;
+                let base = self.read_reg(register(1))? as i64;
+                // Every table entry is a `JMP` to an address
+                let target = base + index * Opcode::Jmp.size() as i64;
+                if target < 0 || target >= program.len() as i64 {
+                    return Err(VmError::JumpOutOfBounds {
+                        pc: instruction_start,
+                        target,
+                    });
+                }
+                jump_to = Some(target as usize);
+            }
+            Opcode::Sumall => {
+                let mut sum = 0i32;
+                for &listed in instruction.register_list(1) {
+                    let value = self.read_reg(listed)?;
+                    sum = self.overflow(sum.overflowing_add(value), instruction_start, opcode)?;
+                }
+                self.write_reg(register(0), sum)?;
+            }
+            Opcode::Clamp => {
+                let value = self.read_reg(register(0))?;
+                let lo = self.read_reg(register(1))?;
+                let hi = self.read_reg(register(2))?;
+                if lo > hi {
+                    return Err(VmError::InvalidRange {
+                        pc: instruction_start,
+                        lo,
+                        hi,
+                    });
+                }
+                self.write_reg(register(0), value.clamp(lo, hi))?;
+            }
+            Opcode::Loadm => {
+                let address = self.read_reg(register(1))?;
+                // Allocated but never written bytes read as the zero `ALLOC` filled them with
+                let byte = usize::try_from(address)
+                    .ok()
+                    .and_then(|address| self.heap.get(address))
+                    .ok_or(VmError::HeapOutOfBounds {
+                        pc: instruction_start,
+                        address,
+                    })?;
+                let value = *byte as i32;
+                self.write_reg(register(0), value)?;
+            }
+            Opcode::Store => {
+                let address = self.read_reg(register(0))?;
+                let value = self.read_reg(register(1))?;
+                let byte = usize::try_from(address)
+                    .ok()
+                    .and_then(|address| self.heap.get_mut(address))
+                    .ok_or(VmError::HeapOutOfBounds {
+                        pc: instruction_start,
+                        address,
+                    })?;
+                *byte = value as u8;
+            }
+            Opcode::Print => {
+                let value = self.read_reg(register(0))?;
+                // Like tracing, a broken output must not abort the program
+                let _ = writeln!(self.output, "{}", value);
+            }
+            Opcode::Igl => unreachable!("illegal opcodes do not decode"),
+        }
+
+        self.pc = next;
+        if halted {
+            return Ok(Some(VmExit::Halted));
+        }
+        if let Some(target) = jump_to {
+            self.pc = target;
+        }
+
+        Ok(None)
+    }
+
+    // The wrapped result of an `overflowing_*` operation, unless the `OverflowMode` forbids it
+    fn overflow(
+        &self,
+        (result, overflowed): (i32, bool),
+        pc: usize,
+        opcode: Opcode,
+    ) -> Result<i32, VmError> {
+        if overflowed && self.overflow_mode == OverflowMode::Error {
+            Err(VmError::ArithmeticOverflow { pc, opcode })
+        } else {
+            Ok(result)
+        }
+    }
+
+    // Quotient and remainder of `lhs / rhs` rounded as the `DivMode` says
+    //
+    // `i32::MIN / -1` overflows and is handled as the `OverflowMode` says.
+    fn divide(&self, lhs: i32, rhs: i32, pc: usize, opcode: Opcode) -> Result<(i32, i32), VmError> {
+        let quotient = self.overflow(lhs.overflowing_div(rhs), pc, opcode)?;
+        let remainder = lhs.wrapping_rem(rhs);
+        if self.div_mode == DivMode::Floor && remainder != 0 && (remainder < 0) != (rhs < 0) {
+            Ok((quotient - 1, remainder + rhs))
+        } else {
+            Ok((quotient, remainder))
+        }
+    }
+
+    // Address a jump target operand points at, see `IMMEDIATE_TARGET` for the encoding
+    //
+    // A register holds an instruction index, which like `SWITCH` has to land inside `program`.
+    fn target(&self, program: &[u8], target: &Operand) -> Result<usize, VmError> {
+        match *target {
+            Operand::Address(address) => Ok(address),
+            Operand::Register(register) => {
+                let value = self.read_reg(register)?;
+                usize::try_from(value)
+                    .ok()
+                    .and_then(|index| index.checked_mul(4))
+                    .filter(|&address| address < program.len())
+                    .ok_or(VmError::JumpOutOfBounds {
+                        pc: self.pc,
+                        target: value as i64 * 4,
+                    })
+            }
+            ref other => unreachable!("{:?} is not a jump target", other),
+        }
+    }
+
+    // Address of the instruction `count` instructions after the one starting at `start`
+    fn instructions_forward(&self, program: &[u8], start: usize, count: usize) -> Option<usize> {
+        let mut address = start;
+        for _ in 0..count {
+            let size = instruction_size(program, address)?;
+            address += self.encoding.width(size);
+        }
+        // Landing right after the last instruction ends the program like running off the end
+        (address <= program.len()).then_some(address)
+    }
+
+    // Address of the instruction `count` instructions before the one starting at `end`
+    //
+    // Instructions can only be decoded forward, so this walks from the start of the program.
+    fn instructions_back(&self, program: &[u8], end: usize, count: usize) -> Option<usize> {
+        let mut boundaries = vec![];
+        let mut address = 0;
+        while address < end {
+            boundaries.push(address);
+            address += self.encoding.width(instruction_size(program, address)?);
+        }
+        boundaries
+            .len()
+            .checked_sub(count)
+            .map(|index| boundaries.get(index).copied().unwrap_or(end))
+    }
+
+    /// Makes room for `additional` more heap bytes, failing with `OutOfMemory` when the host
+    /// refuses them
+    fn reserve_heap(&mut self, additional: usize, pc: usize, size: i32) -> Result<(), VmError> {
+        #[cfg(debug_assertions)]
+        if self
+            .injected_fault
+            .take_if(|fault| *fault == FaultKind::OutOfMemory)
+            .is_some()
+        {
+            return Err(VmError::OutOfMemory { pc, size });
+        }
+        self.heap
+            .try_reserve(additional)
+            .map_err(|_| VmError::OutOfMemory { pc, size })
+    }
+
+    fn register_index(&self, register: u8) -> Result<usize, VmError> {
+        let index = register as usize;
+        if index < self.register_count {
+            Ok(index)
+        } else {
+            Err(VmError::RegisterOutOfBounds {
+                pc: self.pc,
+                register,
+            })
+        }
+    }
+
+    // Value of the register a program names, which may not exist
+    fn read_reg(&self, register: u8) -> Result<i32, VmError> {
+        Ok(self.registers[self.register_index(register)?])
+    }
+
+    // Stores `value` in the register a program names, which may not exist
+    fn write_reg(&mut self, register: u8, value: i32) -> Result<(), VmError> {
+        self.write_reg_tagged(register, value, RegTag::Value)
+    }
+
+    // Like `write_reg`, but tags the register as holding `tag` rather than a value
+    fn write_reg_tagged(&mut self, register: u8, value: i32, tag: RegTag) -> Result<(), VmError> {
+        let index = self.register_index(register)?;
+        self.registers[index] = value;
+        if let Some(tags) = self.register_tags.as_mut() {
+            tags[index] = tag;
+        }
+        Ok(())
+    }
+
+    // Tag of a register `read_reg` already checked, `Unknown` while tagging is off
+    fn reg_tag(&self, register: u8) -> RegTag {
+        self.register_tags
+            .as_ref()
+            .map_or(RegTag::Unknown, |tags| tags[register as usize])
+    }
+
+    pub fn add_byte(&mut self, byte: u8) {
+        self.program_mut().push(byte);
+        self.compiled = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::OperandType;
+
+    #[test]
+    fn test_create_vm() {
+        let vm = Vm::new();
+        assert_eq!(vm.registers[0], 0);
+        assert_eq!(vm.registers[REGISTER_COUNT - 1], 0);
+    }
+
+    #[test]
+    fn test_with_program() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 1, 244, // LOAD 500 to register 0
+            0,   // HLT
+        ]);
+        vm.run();
+
+        assert_eq!(vm.registers[0], 500);
+        assert_eq!(vm.pc, 5);
+    }
+
+    #[test]
+    fn test_cycle_limit() {
+        let mut vm = Vm::with_program(vec![
+            18, 0, // INC register 0
+            6, 255, 0, 0, // JMP 0
+        ]);
+        vm.set_cycle_limit(Some(10));
+
+        assert_eq!(vm.run(), VmExit::CycleLimit);
+        assert_eq!(vm.registers[0], 5);
+    }
+
+    #[test]
+    fn test_run_loop() {
+        let program = vec![
+            18, 0, // INC register 0
+            0, // HLT
+        ];
+        let mut vm = Vm::with_program(program.clone());
+        assert_eq!(vm.run_loop(5, false), VmExit::Halted);
+        assert_eq!(vm.registers[0], 5);
+
+        let mut vm = Vm::with_program(program);
+        assert_eq!(vm.run_loop(5, true), VmExit::Halted);
+        assert_eq!(vm.registers[0], 1);
+
+        // Running off the end is not a clean halt
+        let mut vm = Vm::with_program(vec![
+            18, 0, // INC register 0
+        ]);
+        assert_eq!(vm.run_loop(5, false), VmExit::EndOfProgram);
+        assert_eq!(vm.registers[0], 1);
+    }
+
+    #[test]
+    fn test_watch_register() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 5, // LOAD 5 to register 0
+            1, 1, 0, 6, // LOAD 6 to register 1
+            1, 0, 0, 5, // LOAD 5 to register 0, unchanged
+            19, 0, // DEC register 0
+        ]);
+        vm.watch_register(0);
+        vm.watch_register(0);
+        vm.run();
+
+        assert_eq!(
+            vm.take_watch_events(),
+            vec![
+                WatchEvent {
+                    pc: 0,
+                    register: 0,
+                    old: 0,
+                    new: 5
+                },
+                WatchEvent {
+                    pc: 12,
+                    register: 0,
+                    old: 5,
+                    new: 4
+                },
+            ]
+        );
+        assert!(vm.take_watch_events().is_empty());
+    }
+
+    #[test]
+    fn test_strict_halt() {
+        let program = vec![
+            18, 0, // INC register 0
+        ];
+        let mut lenient = Vm::with_program(program.clone());
+        assert_eq!(lenient.run(), VmExit::EndOfProgram);
+
+        let mut strict = Vm::with_program(program);
+        strict.set_strict_halt(true);
+        assert_eq!(strict.run(), VmExit::Error(VmError::MissingHalt { pc: 2 }));
+        assert_eq!(strict.registers[0], 1);
+
+        let mut halting = Vm::with_program(vec![0]);
+        halting.set_strict_halt(true);
+        assert_eq!(halting.run(), VmExit::Halted);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_inject_fault() {
+        let mut vm = Vm::with_program(vec![
+            18, 0, // INC register 0
+            18, 0, // INC register 0
+        ]);
+        vm.run_once().unwrap();
+        vm.inject_fault(FaultKind::DivisionByZero);
+
+        assert_eq!(vm.run(), VmExit::Error(VmError::DivisionByZero { pc: 2 }));
+        assert_eq!(vm.registers[0], 1);
+    }
+
+    #[test]
+    fn test_run_once_result() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 7, // LOAD 7 to register 0
+            5, 0, 1, 2, // DIV register 0 by register 1, store result in register 2
+        ]);
+
+        assert_eq!(vm.run_once(), Ok(()));
+        assert_eq!(vm.run_once(), Err(VmError::DivisionByZero { pc: 4 }));
+
+        vm.pc = 0;
+        assert_eq!(
+            vm.run().into_result(),
+            Err(VmError::DivisionByZero { pc: 4 })
+        );
+        assert_eq!(VmExit::Halted.into_result(), Ok(()));
+    }
+
+    #[test]
+    fn test_on_halt() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let halted = Rc::new(RefCell::new(vec![]));
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 7, // LOAD 7 to register 0
+            0, // HLT
+        ]);
+        let captured = Rc::clone(&halted);
+        vm.set_on_halt(Box::new(move |vm| {
+            captured
+                .borrow_mut()
+                .push((vm.registers[0], vm.program().len()));
+        }));
+        vm.run();
+        vm.pc = 0;
+        vm.run();
+
+        assert_eq!(*halted.borrow(), vec![(7, 5)]);
+    }
+
+    #[test]
+    fn test_coverage() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 2, // LOAD 2 to register 0
+            18, 0, // INC register 0
+            0, // HLT
+        ]);
+        vm.run();
+        assert_eq!(vm.coverage(), None);
+
+        vm.enable_coverage();
+        vm.pc = 0;
+        vm.run();
+        let mut coverage = vm.coverage().unwrap().clone();
+        assert_eq!(
+            coverage.executed,
+            HashSet::from([Opcode::Load, Opcode::Inc, Opcode::Hlt])
+        );
+
+        let mut other = Vm::with_program(vec![
+            19, 0, // DEC register 0
+        ]);
+        other.enable_coverage();
+        other.run();
+        coverage.merge(other.coverage().unwrap());
+        assert_eq!(coverage.executed.len(), 4);
+        assert!(!coverage.uncovered().contains(&Opcode::Dec));
+        assert_eq!(coverage.uncovered()[..2], [Opcode::Add, Opcode::Sub]);
+        assert!(coverage.to_string().starts_with(&format!(
+            "Executed 4 of {} opcodes, never executed: ADD SUB MUL",
+            Opcode::all().len()
+        )));
+    }
+
+    #[test]
+    fn test_last_opcode() {
+        let mut vm = Vm::new();
+        assert_eq!(vm.last_opcode(), None);
+
+        vm.set_program(vec![1, 0, 1, 244]).unwrap();
+        vm.run_once().unwrap();
+        assert_eq!(vm.last_opcode(), Some(Opcode::Load));
+    }
+
+    #[test]
+    fn test_trace_to_file() {
+        let path = std::env::temp_dir().join("vm_test_trace_to_file.txt");
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 1, // LOAD 1 to register 0
+            18, 0, // INC register 0
+            0, // HLT
+        ]);
+        vm.trace_to_file(&path).unwrap();
+        vm.run();
+
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], "0004: Inc 00 | $0 = 2");
+    }
+
+    #[test]
+    fn test_truncated_operands() {
+        let mut vm = Vm::with_program(vec![1, 0]);
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::ProgramOutOfBounds { pc: 2 })
+        );
+
+        let mut vm = Vm::with_program(vec![2, 0]);
+        assert_eq!(vm.run_once(), Err(VmError::ProgramOutOfBounds { pc: 2 }));
+
+        let mut vm = Vm::with_program(vec![6, 255, 0]);
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::ProgramOutOfBounds { pc: 2 })
+        );
+
+        let mut vm = Vm::with_program(vec![42]);
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::ProgramOutOfBounds { pc: 1 })
+        );
+        assert_eq!(vm.pc, 1);
+    }
+
+    #[test]
+    fn test_next_32_bits() {
+        let program = [0x12, 0x34, 0x56, 0x78, 0xFF, 0xFF, 0xFF];
+        let mut reader = OperandReader {
+            program: &program,
+            offset: 0,
+        };
+
+        assert_eq!(reader.next_32_bits(), Ok(0x1234_5678));
+        assert_eq!(reader.offset, 4);
+        assert_eq!(
+            reader.next_32_bits(),
+            Err(VmError::ProgramOutOfBounds { pc: 4 })
+        );
+        assert_eq!(reader.offset, 4);
+    }
+
+    #[test]
+    fn test_decode_operands() {
+        let program = [
+            1, 2, 255, 251, // LOAD -5 to register 2
+            6, 3, 0, 0, // JMP to register 3
+            23, 0, 2, 4, 5, // SUMALL registers 4 and 5, store result in register 0
+        ];
+        assert_eq!(
+            decode_operands(&program, 0, Opcode::Load),
+            Ok(DecodedInstruction {
+                opcode: Opcode::Load,
+                operands: vec![Operand::Register(2), Operand::Number(-5)],
+                size: 4,
+            })
+        );
+        assert_eq!(
+            decode_operands(&program, 4, Opcode::Jmp).unwrap().operands,
+            vec![Operand::Register(3)]
+        );
+        assert_eq!(
+            decode_operands(&program, 8, Opcode::Sumall),
+            Ok(DecodedInstruction {
+                opcode: Opcode::Sumall,
+                operands: vec![Operand::Register(0), Operand::RegisterList(vec![4, 5])],
+                size: 5,
+            })
+        );
+        assert_eq!(
+            decode_operands(&program[..12], 8, Opcode::Sumall),
+            Err(VmError::ProgramOutOfBounds { pc: 12 })
+        );
+    }
+
+    #[test]
+    fn test_read_write_reg() {
+        let mut vm = Vm::new();
+
+        assert_eq!(vm.write_reg(3, 7), Ok(()));
+        assert_eq!(vm.read_reg(3), Ok(7));
+        assert_eq!(
+            vm.read_reg(200),
+            Err(VmError::RegisterOutOfBounds {
+                pc: 0,
+                register: 200
+            })
+        );
+        assert!(vm.write_reg(32, 7).is_err());
+    }
+
+    #[test]
+    fn test_bad_register_halts() {
+        let mut vm = Vm::with_program(vec![
+            2, 0, 1, 200, // ADD register 0 and register 1, store result in register 200
+            18, 0, // INC register 0, never reached
+        ]);
+        vm.run();
+
+        assert_eq!(vm.pc, 0);
+        assert_eq!(vm.registers[0], 0);
+
+        let mut vm = Vm::with_program(vec![
+            9, 0, 40, // EQ register 0 and register 40
+            18, 0, // INC register 0, never reached
+        ]);
+        vm.run();
+
+        assert_eq!(vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_execute_borrowed_program() {
+        let program = [
+            2, 0, 1, 2, // ADD register 0 and register 1, store result in register 2
+            0, // HLT
+        ];
+
+        let mut vm1 = Vm::new();
+        vm1.registers[0] = 1;
+        vm1.registers[1] = 2;
+        let mut vm2 = Vm::new();
+        vm2.registers[0] = 10;
+        vm2.registers[1] = 20;
+
+        assert_eq!(vm1.execute(&program), VmExit::Halted);
+        assert_eq!(vm2.execute(&program), VmExit::Halted);
+        assert_eq!(vm1.registers[2], 3);
+        assert_eq!(vm2.registers[2], 30);
+        assert!(vm1.program().is_empty());
+        assert!(vm2.program().is_empty());
+    }
+
+    #[test]
+    fn test_run_with_state() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 3, // LOAD 3 to register 0
+            2, 0, 0, 1, // ADD register 0 and register 0, store result in register 1
+            0, // HLT
+        ]);
+        let state = vm.run_with_state();
+
+        assert_eq!(state.exit, VmExit::Halted);
+        assert_eq!(state.pc, 9);
+        assert_eq!(state.registers[..2], [3, 6]);
+        assert_eq!(state.registers, vm.registers);
+        assert_eq!((state.rem, state.cmp), (0, 0));
+    }
+
+    #[test]
+    fn test_remainder_and_comparison_flag() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 255, 245, // LOAD -11 to register 0
+            1, 1, 0, 4, // LOAD 4 to register 1
+            5, 0, 1, 2, // DIV register 0 and register 1, store result in register 2
+            12, 0, 1, // LT register 0 and register 1
+            0, // HLT
+        ]);
+        let state = vm.run_with_state();
+
+        assert_eq!(state.registers[2], -2);
+        assert_eq!(state.rem as i32, -3);
+        assert_eq!(state.cmp, 1);
+        assert_eq!(vm.remainder(), -3);
+        assert!(vm.comparison_flag());
+    }
+
+    #[test]
+    fn test_run_compiled() {
+        let program = vec![
+            1, 0, 0, 100, // LOAD 100 to register 0
+            1, 2, 0, 3, // LOAD 3 to register 2
+            2, 1, 2, 1, // ADD register 1 and register 2, store result in register 1
+            18, 3, // INC register 3
+            12, 3, 0, // LT register 3 and register 0
+            15, 255, 0, 8, // JEQ 8
+            0, // HLT
+        ];
+        let mut interpreted = Vm::with_program(program.clone());
+        let mut compiled = Vm::with_program(program);
+        compiled.compile().unwrap();
+
+        assert_eq!(interpreted.run_with_state().exit, VmExit::Halted);
+        assert_eq!(compiled.run_compiled(), VmExit::Halted);
+        assert_eq!(compiled.registers, interpreted.registers);
+        assert_eq!(compiled.pc, interpreted.pc);
+        assert_eq!(compiled.registers[1], 300);
+    }
+
+    #[test]
+    fn test_compile_rejects_truncated_program() {
+        let mut vm = Vm::with_program(vec![0, 1, 0]);
+
+        assert_eq!(vm.compile(), Err(VmError::ProgramOutOfBounds { pc: 3 }));
+    }
+
+    #[test]
+    #[allow(deprecated)] // Edits the program behind the VM's back on purpose
+    fn test_compiled_program_changed() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 5, // LOAD 5 to register 0
+        ]);
+        vm.compile().unwrap();
+        vm.program[3] = 7;
+        assert_eq!(vm.run_compiled(), VmExit::EndOfProgram);
+        assert_eq!(vm.registers[0], 7);
+
+        vm.pc = 0;
+        vm.compile().unwrap();
+        vm.add_byte(18);
+        vm.add_byte(0); // INC register 0
+        assert_eq!(vm.run_compiled(), VmExit::EndOfProgram);
+        assert_eq!(vm.registers[0], 8);
+        assert!(vm.compiled.is_none());
+    }
+
+    #[test]
+    fn test_step() {
+        let mut vm = Vm::with_program(vec![
+            2, 0, 1, 2, // ADD register 0 and register 1, store result in register 2
+            0, // HLT
+        ]);
+        vm.registers[0] = 3;
+        vm.registers[1] = 4;
+
+        assert_eq!(
+            vm.step(),
+            Some(StepDelta {
+                executed: Opcode::Add,
+                pc: 0,
+                changed_registers: vec![(2, 7)],
+                halted: false,
+            })
+        );
+        assert_eq!(
+            vm.step(),
+            Some(StepDelta {
+                executed: Opcode::Hlt,
+                pc: 4,
+                changed_registers: vec![],
+                halted: true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_step_past_end() {
+        let mut vm = Vm::with_program(vec![0]);
+        vm.pc = 1;
+
+        assert_eq!(vm.step(), None);
+    }
+
+    #[test]
+    fn test_used_registers() {
+        let mut vm = Vm::with_program(vec![
+            1, 3, 0, 7, // LOAD 7 to register 3
+            1, 1, 255, 254, // LOAD -2 to register 1
+        ]);
+        vm.run();
+
+        assert_eq!(vm.used_registers(), vec![(1, -2), (3, 7)]);
+    }
+
+    #[test]
+    fn test_pending_instruction_bytes() {
+        let mut vm = Vm::new();
+        assert_eq!(vm.pending_instruction_bytes(), None);
+
+        vm.add_byte(1);
+        vm.add_byte(0);
+        assert_eq!(vm.pending_instruction_bytes(), Some((2, 4)));
+
+        vm.add_byte(0);
+        vm.add_byte(5);
+        vm.add_byte(0);
+        assert_eq!(vm.pending_instruction_bytes(), Some((4, 4)));
+    }
+
+    #[test]
+    fn test_set_program() {
+        let mut vm = Vm::new();
+        vm.pc = 3;
+
+        assert_eq!(vm.set_program(vec![0, 18, 1, 0]), Ok(()));
+        assert_eq!(vm.program(), &[0, 18, 1, 0]);
+        assert_eq!(vm.pc, 0);
+        assert_eq!(
+            vm.set_program(vec![0, 1, 0, 5]),
+            Err(VmError::ProgramOutOfBounds { pc: 1 })
+        );
+        assert_eq!(
+            vm.set_program(vec![0, 255]),
+            Err(VmError::IllegalOpcode { pc: 1, byte: 255 })
+        );
+        assert_eq!(vm.program(), &[0, 18, 1, 0]);
+    }
+
+    #[test]
+    fn test_opcode_hlt() {
+        let mut vm = Vm::with_program(vec![0, 0, 0, 0]);
+        vm.run();
+
+        assert_eq!(vm.pc, 1);
+    }
+
+    #[test]
+    fn test_opcode_igl() {
+        let mut vm = Vm::with_program(vec![255, 0, 0, 0]);
+
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::IllegalOpcode { pc: 0, byte: 255 })
+        );
+        assert_eq!(vm.pc, 1);
+    }
+
+    #[test]
+    fn test_illegal_opcode_message() {
+        let error = VmError::IllegalOpcode { pc: 4, byte: 255 };
+
+        assert_eq!(error.to_string(), "Unrecognized opcode 0xFF found at 4");
+    }
+
+    #[test]
+    fn test_opcode_load() {
+        let mut vm = Vm::with_program(vec![1, 0, 1, 244]);
+        vm.run_once().unwrap();
+
+        assert_eq!(vm.registers[0], 500);
+    }
+
+    #[test]
+    fn test_opcode_load_negative() {
+        let mut vm = Vm::with_program(vec![1, 0, 255, 251]);
+        vm.run_once().unwrap();
+
+        assert_eq!(vm.registers[0], -5);
+    }
+
+    #[test]
+    fn test_load_sign_extends() {
+        // Immediates of 32768 and up were loaded as is before LOAD became signed
+        let cases = [
+            ([0x7F, 0xFF], 32767),
+            ([0x80, 0x00], -32768),
+            ([0xFF, 0xFF], -1),
+        ];
+        for ([high, low], expected) in cases {
+            let mut vm = Vm::with_program(vec![1, 0, high, low]); // LOAD to register 0
+            vm.run();
+            assert_eq!(vm.registers[0], expected);
+        }
+    }
+
+    #[test]
+    fn test_opcode_loadif() {
+        let mut vm = Vm::with_program(vec![
+            9, 0, 0, // EQ register 0 and register 0
+            30, 1, 0, 7, // LOADIF 7 to register 1
+            10, 0, 0, // NEQ register 0 and register 0
+            30, 2, 0, 7, // LOADIF 7 to register 2
+        ]);
+        vm.run();
+
+        assert_eq!(vm.registers[1], 7);
+        assert_eq!(vm.registers[2], 0);
+    }
+
+    #[test]
+    fn test_opcode_add() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 250, // LOAD 250 to register 0
+            1, 1, 0, 250, // LOAD 250 to register 1
+            2, 0, 1, 2,
+        ]); // ADD register 0 and register 1, store result in register 2
+        vm.run();
+
+        assert_eq!(vm.registers[0], 250);
+        assert_eq!(vm.registers[1], 250);
+        assert_eq!(vm.registers[2], 500);
+    }
+
+    #[test]
+    fn test_opcode_sub() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 1, 244, // LOAD 500 to register 0
+            1, 1, 0, 250, // LOAD 250 to register 1
+            3, 0, 1, 2, // SUB register 0 and register 1, store result in register 2
+        ]);
+        vm.run();
+
+        assert_eq!(vm.registers[0], 500);
+        assert_eq!(vm.registers[1], 250);
+        assert_eq!(vm.registers[2], 250);
+    }
+
+    #[test]
+    fn test_opcode_mul() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 2, // LOAD 2 to register 0
+            1, 1, 0, 3, // LOAD 3 to register 1
+            4, 0, 1, 2, // MUL register 0 and register 1, store result in register 2
+        ]);
+        vm.run();
+
+        assert_eq!(vm.registers[0], 2);
+        assert_eq!(vm.registers[1], 3);
+        assert_eq!(vm.registers[2], 6);
+    }
+
+    #[test]
+    fn test_opcode_spaceship() {
+        for (a, b, expected) in [(-5, 3, -1), (-4, -4, 0), (7, -7, 1)] {
+            let mut vm = Vm::with_program(vec![
+                31, 0, 1, 2, // SPACESHIP register 0 and register 1, store result in register 2
+            ]);
+            vm.registers[0] = a;
+            vm.registers[1] = b;
+            vm.registers[2] = 9;
+            vm.run();
+
+            assert_eq!(vm.registers[2], expected, "{} <=> {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_opcode_mulh() {
+        for (a, b, high) in [
+            (0x4000_0000, 8, 2),
+            (i32::MAX, i32::MAX, 0x3FFF_FFFF),
+            (i32::MIN, i32::MIN, 0x4000_0000),
+            (-0x4000_0000, 8, -2),
+            (3, 5, 0),
+        ] {
+            let mut vm = Vm::with_program(vec![
+                29, 0, 1, 2, // MULH register 0 and register 1, store result in register 2
+            ]);
+            vm.registers[0] = a;
+            vm.registers[1] = b;
+            vm.run();
+
+            assert_eq!(vm.registers[2], high, "{} * {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_opcode_div() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 11, // LOAD 10 to register 0
+            1, 1, 0, 2, // LOAD 2 to register 1
+            5, 0, 1, 2, // DIV register 0 and register 1, store result in register 2
+        ]);
+        vm.run();
+
+        assert_eq!(vm.registers[0], 11);
+        assert_eq!(vm.registers[1], 2);
+        assert_eq!(vm.registers[2], 5);
+        assert_eq!(vm.rem, 1);
+    }
+
+    #[test]
+    fn test_arithmetic_overflow() {
+        let cases = [
+            (2, i32::MAX, 1, Opcode::Add),
+            (3, i32::MIN, 1, Opcode::Sub),
+            (4, i32::MAX, 2, Opcode::Mul),
+        ];
+        for (byte, lhs, rhs, opcode) in cases {
+            let mut vm = Vm::with_program(vec![
+                byte, 0, 1,
+                2, // ADD, SUB or MUL register 0 and register 1, store result in register 2
+            ]);
+            vm.registers[0] = lhs;
+            vm.registers[1] = rhs;
+
+            assert_eq!(
+                vm.run(),
+                VmExit::Error(VmError::ArithmeticOverflow { pc: 0, opcode })
+            );
+            assert_eq!(vm.registers[2], 0);
+        }
+    }
+
+    #[test]
+    fn test_inc_dec_overflow() {
+        let cases = [
+            (18, i32::MAX, i32::MIN, Opcode::Inc),
+            (27, i32::MAX, i32::MIN, Opcode::Incif),
+            (19, i32::MIN, i32::MAX, Opcode::Dec),
+        ];
+        for (byte, value, wrapped, opcode) in cases {
+            let program = vec![byte, 0]; // INC, INCIF or DEC register 0
+            let mut vm = Vm::with_program(program.clone());
+            vm.registers[0] = value;
+            vm.cmp = 1;
+            assert_eq!(
+                vm.run(),
+                VmExit::Error(VmError::ArithmeticOverflow { pc: 0, opcode })
+            );
+            assert_eq!(vm.registers[0], value);
+
+            let mut vm = Vm::from_config(VmConfig {
+                overflow_mode: OverflowMode::Wrap,
+                ..VmConfig::default()
+            });
+            vm.set_program(program).unwrap();
+            vm.registers[0] = value;
+            vm.cmp = 1;
+            assert_eq!(vm.run(), VmExit::EndOfProgram);
+            assert_eq!(vm.registers[0], wrapped);
+        }
+    }
+
+    #[test]
+    fn test_sumall_overflow() {
+        let program = vec![
+            23, 0, 2, 1, 2, // SUMALL registers 1 and 2, store result in register 0
+        ];
+        let mut vm = Vm::with_program(program.clone());
+        vm.registers[1] = i32::MAX;
+        vm.registers[2] = 1;
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::ArithmeticOverflow {
+                pc: 0,
+                opcode: Opcode::Sumall
+            })
+        );
+        assert_eq!(vm.registers[0], 0);
+
+        let mut vm = Vm::from_config(VmConfig {
+            overflow_mode: OverflowMode::Wrap,
+            ..VmConfig::default()
+        });
+        vm.set_program(program).unwrap();
+        vm.registers[1] = i32::MAX;
+        vm.registers[2] = 1;
+        assert_eq!(vm.run(), VmExit::EndOfProgram);
+        assert_eq!(vm.registers[0], i32::MIN);
+    }
+
+    #[test]
+    fn test_from_config() {
+        let program = vec![
+            1, 0, 0, 6, // LOAD 6 to register 0
+            17, 0, // ALLOC register 0
+            1, 1, 127, 255, // LOAD 32767 to register 1
+            4, 1, 1, 1, // MUL register 1 and register 1, store result in register 1
+            4, 1, 1, 1, // MUL register 1 and register 1, store result in register 1
+            1, 7, 0, 1, // LOAD 1 to register 7
+            1, 8, 0, 1, // LOAD 1 to register 8
+        ];
+        let config = VmConfig {
+            register_count: 8,
+            max_heap: Some(10),
+            max_cycles: Some(10),
+            overflow_mode: OverflowMode::Wrap,
+            div_mode: DivMode::Floor,
+        };
+
+        let mut vm = Vm::from_config(config);
+        vm.set_program(program.clone()).unwrap();
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::RegisterOutOfBounds {
+                pc: 22,
+                register: 8
+            })
+        );
+        assert_eq!(
+            vm.registers[1],
+            (32767 * 32767i32).wrapping_mul(32767 * 32767)
+        );
+        assert_eq!(vm.div_mode, DivMode::Floor);
+
+        let mut vm = Vm::from_config(VmConfig {
+            max_cycles: Some(3),
+            ..config
+        });
+        vm.set_program(program.clone()).unwrap();
+        assert_eq!(vm.run(), VmExit::CycleLimit);
+
+        let mut vm = Vm::from_config(VmConfig {
+            max_heap: Some(5),
+            ..config
+        });
+        vm.set_program(program).unwrap();
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::AllocTooLarge { pc: 4, size: 6 })
+        );
+    }
+
+    #[test]
+    fn test_opcode_div_by_zero() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 11, // LOAD 11 to register 0
+            1, 1, 0, 0, // LOAD 0 to register 1
+            5, 0, 1, 2, // DIV register 0 and register 1, store result in register 2
+        ]);
+        vm.registers[2] = 3;
+
+        assert_eq!(vm.run(), VmExit::Error(VmError::DivisionByZero { pc: 8 }));
+        assert_eq!(vm.registers[2], 3);
+        assert_eq!(vm.rem, 0);
+    }
+
+    #[test]
+    fn test_div_mode() {
+        let program = vec![
+            1, 0, 255, 249, // LOAD -7 to register 0
+            1, 1, 0, 2, // LOAD 2 to register 1
+            5, 0, 1, 2, // DIV register 0 and register 1, store result in register 2
+        ];
+        let mut trunc = Vm::with_program(program.clone());
+        trunc.run();
+        assert_eq!(trunc.registers[2], -3);
+        assert_eq!(trunc.rem as i32, -1);
+
+        let mut floor = Vm::with_program(program);
+        floor.set_div_mode(DivMode::Floor);
+        floor.run();
+        assert_eq!(floor.registers[2], -4);
+        assert_eq!(floor.rem as i32, 1);
+    }
+
+    #[test]
+    fn test_opcode_call_ret() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 1, // LOAD 1 to register 0
+            32, 255, 0, 11, // CALL 11
+            18, 1, // INC register 1
+            0, // HLT
+            2, 0, 0, 0,  // ADD register 0 and register 0, store result in register 0
+            33, // RET
+        ]);
+
+        assert_eq!(vm.run(), VmExit::Halted);
+        assert_eq!(vm.registers[0], 2);
+        assert_eq!(vm.registers[1], 1);
+        assert_eq!(vm.call_depth(), 0);
+    }
+
+    #[test]
+    fn test_ret_without_call() {
+        let mut vm = Vm::with_program(vec![33]);
+
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::ReturnWithoutCall { pc: 0 })
+        );
+    }
+
+    #[test]
+    fn test_step_over() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 1, // LOAD 1 to register 0
+            32, 255, 0, 11, // CALL 11
+            18, 1, // INC register 1
+            0, // HLT
+            32, 255, 0, 16, // CALL 16
+            33, // RET
+            2, 0, 0, 0,  // ADD register 0 and register 0, store result in register 0
+            33, // RET
+        ]);
+
+        assert_eq!(vm.step_over(), None);
+        assert_eq!(vm.pc, 4);
+        // Both nested subroutines run to completion
+        assert_eq!(vm.step_over(), None);
+        assert_eq!(vm.pc, 8);
+        assert_eq!(vm.registers[0], 2);
+        assert_eq!(vm.call_depth(), 0);
+        assert_eq!(vm.step_over(), None);
+        assert_eq!(vm.registers[1], 1);
+        assert_eq!(vm.step_over(), Some(VmExit::Halted));
     }
 
     #[test]
-    fn test_opcode_sub() {
-        let mut vm = Vm::new();
-        vm.program = vec![
-            1, 0, 1, 244, // LOAD 500 to register 0
-            1, 1, 0, 250, // LOAD 250 to register 1
-            3, 0, 1, 2, // SUB register 0 and register 1, store result in register 2
-        ];
+    fn test_opcode_mod() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 11, // LOAD 11 to register 0
+            1, 1, 0, 3, // LOAD 3 to register 1
+            16, 0, 1, 2, // MOD register 0 and register 1, store result in register 2
+            1, 0, 255, 249, // LOAD -7 to register 0
+            1, 1, 0, 2, // LOAD 2 to register 1
+            16, 0, 1, 3, // MOD register 0 and register 1, store result in register 3
+        ]);
         vm.run();
+        assert_eq!(vm.registers[2], 2);
+        assert_eq!(vm.registers[3], -1);
+        assert_eq!(vm.rem, 0);
 
-        assert_eq!(vm.registers[0], 500);
-        assert_eq!(vm.registers[1], 250);
-        assert_eq!(vm.registers[2], 250);
+        vm.pc = 0;
+        vm.set_div_mode(DivMode::Floor);
+        vm.run();
+        assert_eq!(vm.registers[3], 1);
     }
 
     #[test]
-    fn test_opcode_mul() {
-        let mut vm = Vm::new();
-        vm.program = vec![
-            1, 0, 0, 2, // LOAD 2 to register 0
-            1, 1, 0, 3, // LOAD 3 to register 1
-            4, 0, 1, 2, // MUL register 0 and register 1, store result in register 2
+    fn test_opcode_shl_shr() {
+        let mut vm = Vm::with_program(vec![
+            34, 0, 1, 2, // SHL register 0 by register 1, store result in register 2
+            35, 0, 1, 3, // SHR register 0 by register 1, store result in register 3
+        ]);
+        let cases = [
+            (5, 2, 20, 1),
+            (-8, 1, -16, -4),
+            (1, 31, i32::MIN, 0),
+            (1, 32, 0, 0),
+            (-1, 40, 0, -1),
+            (7, -1, 0, 0),
         ];
-        vm.run();
+        for (value, amount, shl, shr) in cases {
+            vm.pc = 0;
+            vm.registers[0] = value;
+            vm.registers[1] = amount;
+            assert_eq!(vm.run(), VmExit::EndOfProgram);
+            assert_eq!(
+                (vm.registers[2], vm.registers[3]),
+                (shl, shr),
+                "{} {}",
+                value,
+                amount
+            );
+        }
+    }
 
-        assert_eq!(vm.registers[0], 2);
-        assert_eq!(vm.registers[1], 3);
-        assert_eq!(vm.registers[2], 6);
+    #[test]
+    fn test_division_overflow() {
+        for (byte, opcode) in [(5, Opcode::Div), (16, Opcode::Mod)] {
+            let program = vec![
+                byte, 0, 1,
+                2, // DIV or MOD register 0 and register 1, store result in register 2
+            ];
+            let mut vm = Vm::with_program(program.clone());
+            vm.registers[0] = i32::MIN;
+            vm.registers[1] = -1;
+            vm.registers[2] = 3;
+            assert_eq!(
+                vm.run(),
+                VmExit::Error(VmError::ArithmeticOverflow { pc: 0, opcode })
+            );
+            assert_eq!(vm.registers[2], 3);
+
+            let mut vm = Vm::from_config(VmConfig {
+                overflow_mode: OverflowMode::Wrap,
+                ..VmConfig::default()
+            });
+            vm.set_program(program).unwrap();
+            vm.registers[0] = i32::MIN;
+            vm.registers[1] = -1;
+            assert_eq!(vm.run(), VmExit::EndOfProgram);
+            let expected = if opcode == Opcode::Div { i32::MIN } else { 0 };
+            assert_eq!(vm.registers[2], expected);
+        }
     }
 
     #[test]
-    fn test_opcode_div() {
-        let mut vm = Vm::new();
-        vm.program = vec![
-            1, 0, 0, 11, // LOAD 10 to register 0
-            1, 1, 0, 2, // LOAD 2 to register 1
+    fn test_try_run() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 7, // LOAD 7 to register 0
             5, 0, 1, 2, // DIV register 0 and register 1, store result in register 2
-        ];
-        vm.run();
+        ]);
+        assert_eq!(vm.try_run(), Err(VmError::DivisionByZero { pc: 4 }));
 
-        assert_eq!(vm.registers[0], 11);
-        assert_eq!(vm.registers[1], 2);
-        assert_eq!(vm.registers[2], 5);
-        assert_eq!(vm.rem, 1);
+        vm.registers[1] = 2;
+        vm.pc = 0;
+        assert_eq!(vm.try_run(), Ok(()));
+        assert_eq!(vm.registers[2], 3);
+    }
+
+    #[test]
+    fn test_opcode_mod_by_zero() {
+        let mut vm = Vm::with_program(vec![
+            16, 0, 1, 2, // MOD register 0 and register 1, store result in register 2
+        ]);
+
+        assert_eq!(vm.run(), VmExit::Error(VmError::DivisionByZero { pc: 0 }));
     }
 
     #[test]
     fn test_opcode_jmp() {
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 0, // LOAD 0 to register 0
             6, 0, 0, 0, // JMP to register 0
-        ];
-        vm.run_once();
+        ]);
+        vm.run_once().unwrap();
         assert_eq!(vm.registers[0], 0);
-        vm.run_once();
+        vm.run_once().unwrap();
         assert_eq!(vm.pc, 0);
     }
 
     #[test]
-    fn test_opcode_jmpb() {
+    fn test_jmp_register_out_of_bounds() {
+        let program = vec![
+            1, 0, 255, 255, // LOAD -1 to register 0
+            6, 0, 0, 0, // JMP to register 0
+        ];
         let mut vm = Vm::new();
-        vm.program = vec![
+        assert_eq!(
+            vm.execute(&program),
+            VmExit::Error(VmError::JumpOutOfBounds { pc: 4, target: -4 })
+        );
+
+        let mut vm = Vm::with_program(vec![
+            6, 0, 0, 0, // JMP to register 0
+        ]);
+        vm.registers[0] = 1;
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::JumpOutOfBounds { pc: 0, target: 4 })
+        );
+    }
+
+    #[test]
+    fn test_opcode_jmpb() {
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 1, // LOAD 1 to register 0
-            8, 0, 0, 0, // JMPB to register 0
-        ];
-        vm.run_once();
-        assert_eq!(vm.pc, 4);
-        vm.run_once();
+            18, 1, // INC register 1
+            8, 0, // JMPB to register 0
+        ]);
+        vm.run_once().unwrap();
+        vm.run_once().unwrap();
+        assert_eq!(vm.pc, 6);
+        // Counted from the start of the `JMPB`, so 1 is the `INC` just before it
+        vm.run_once().unwrap();
         assert_eq!(vm.pc, 4);
+
+        vm.pc = 6;
+        vm.registers[0] = 2;
+        vm.run_once().unwrap();
+        assert_eq!(vm.pc, 0);
+
+        vm.pc = 6;
+        vm.registers[0] = 0;
+        vm.run_once().unwrap();
+        assert_eq!(vm.pc, 6);
+
+        for count in [3, -1, i32::MIN] {
+            vm.pc = 6;
+            vm.registers[0] = count;
+            assert_eq!(
+                vm.run_once(),
+                Err(VmError::RelativeJumpOutOfBounds { pc: 6, count })
+            );
+        }
     }
 
     #[test]
     fn test_opcode_jmpf() {
-        let mut vm = Vm::new();
-        vm.program = vec![
-            1, 0, 0, 1, // LOAD 1 to register 0
-            7, 0, 0, 0, // JMPF to register 0
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 1, // LOAD 1 to register 0
-        ];
-        vm.run_once();
+            7, 0, // JMPF to register 0
+            1, 0, 0, 1, // LOAD 1 to register 0, skipped
+        ]);
+        vm.run_once().unwrap();
         assert_eq!(vm.pc, 4);
-        vm.run_once();
-        assert_eq!(vm.pc, 12);
+        vm.run_once().unwrap();
+        assert_eq!(vm.pc, 10);
+    }
+
+    #[test]
+    fn test_opcode_jmpf_mixed_widths() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 2, // LOAD 2 to register 0
+            7, 0, // JMPF over 2 instructions
+            9, 0, 0, // EQ register 0 and register 0, skipped
+            18, 1, // INC register 1, skipped
+            18, 2, // INC register 2
+            8, 0, // JMPB over 2 instructions
+        ]);
+        vm.run_once().unwrap();
+        vm.run_once().unwrap();
+        assert_eq!(vm.pc, 11);
+        assert_eq!(vm.cmp, 0);
+
+        vm.pc = 13;
+        vm.run_once().unwrap();
+        assert_eq!(vm.pc, 9);
+
+        vm.registers[0] = 10;
+        vm.pc = 4;
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::RelativeJumpOutOfBounds { pc: 4, count: 10 })
+        );
+    }
+
+    #[test]
+    fn test_opcode_bt() {
+        let mut vm = Vm::with_program(vec![
+            37, 0, 1, // BT bit register 1 of register 0
+        ]);
+        let cases = [
+            (0b100, 2, 1),
+            (0b100, 1, 0),
+            (-1, 31, 1),
+            (i32::MAX, 31, 0),
+            (1, 32, 1),
+            (2, -31, 1),
+        ];
+        for (value, bit, expected) in cases {
+            vm.pc = 0;
+            vm.registers[0] = value;
+            vm.registers[1] = bit;
+            vm.run();
+            assert_eq!(vm.cmp, expected, "bit {} of {}", bit, value);
+        }
+    }
+
+    #[test]
+    fn test_register_out_of_bounds() {
+        for &opcode in Opcode::all() {
+            let operands = opcode.operands().operands;
+            if operands.first() != Some(&OperandType::Register) {
+                continue;
+            }
+            let mut program = vec![opcode.into()];
+            program.resize(opcode.size(), 200);
+            // An empty list, so that the registers it would name are not missing
+            if operands.last() == Some(&OperandType::RegisterList) {
+                *program.last_mut().unwrap() = 0;
+            }
+            let mut vm = Vm::with_program(program);
+
+            assert!(
+                matches!(
+                    vm.run(),
+                    VmExit::Error(VmError::RegisterOutOfBounds { register: 200, .. })
+                ),
+                "{:?}",
+                opcode
+            );
+        }
     }
 
     #[test]
     fn test_opcode_eq() {
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 1, // LOAD 1 to register 0
             1, 1, 0, 1, // LOAD 1 to register 1
             9, 0, 1, 0, // EQ register 0 and register 1, store result in register 2
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 1);
 
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 1, // LOAD 1 to register 0
             1, 1, 0, 2, // LOAD 2 to register 1
             9, 0, 1, 0, // EQ register 0 and register 1, store result in register 2
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 0);
     }
 
     #[test]
     fn test_opcode_neq() {
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 1, // LOAD 1 to register 0
             1, 1, 0, 2, // LOAD 2 to register 1
             10, 0, 1, 0, // NEQ register 0 and register 1, store result in register 2
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 1);
 
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 1, // LOAD 1 to register 0
             1, 1, 0, 1, // LOAD 1 to register 1
             10, 0, 1, 0, // NEQ register 0 and register 1, store result in register 2
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 0);
     }
 
     #[test]
     fn test_opcode_gt() {
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 2, // LOAD 2 to register 0
             1, 1, 0, 1, // LOAD 1 to register 1
             11, 0, 1, 0, // GT register 0 and register 1, store result in register 2
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 1);
 
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 1, // LOAD 1 to register 0
             1, 1, 0, 2, // LOAD 2 to register 1
             11, 0, 1, 0, // GT register 0 and register 1, store result in register 2
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 0);
     }
 
     #[test]
     fn test_opcode_lt() {
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 1, // LOAD 1 to register 0
             1, 1, 0, 2, // LOAD 2 to register 1
             12, 0, 1, 0, // LT register 0 and register 1, store result in register 2
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 1);
 
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 2, // LOAD 2 to register 0
             1, 1, 0, 1, // LOAD 1 to register 1
             12, 0, 1, 0, // LT register 0 and register 1, store result in register 2
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 0);
     }
 
     #[test]
     fn test_opcode_gte() {
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 2, // LOAD 2 to register 0
             1, 1, 0, 1, // LOAD 1 to register 1
             13, 0, 1, 0, // GTE register 0 and register 1, store result in register 2
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 1);
 
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 1, // LOAD 1 to register 0
             1, 1, 0, 1, // LOAD 1 to register 1
             13, 0, 1, 0, // GTE register 0 and register 1, store result in register 2
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 1);
 
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 1, // LOAD 1 to register 0
             1, 1, 0, 2, // LOAD 2 to register 1
             13, 0, 1, 0, // GTE register 0 and register 1, store result in register 2
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 0);
     }
 
     #[test]
     fn test_opcode_lte() {
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 1, // LOAD 1 to register 0
             1, 1, 0, 2, // LOAD 2 to register 1
             14, 0, 1, 0, // LTE register 0 and register 1, store result in register 2
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 1);
 
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 2, // LOAD 2 to register 0
             1, 1, 0, 1, // LOAD 1 to register 1
             14, 0, 1, 0, // LTE register 0 and register 1, store result in register 2
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 0);
 
-        let mut vm = Vm::new();
-        vm.program = vec![
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 1, // LOAD 1 to register 0
             1, 1, 0, 1, // LOAD 1 to register 1
             14, 0, 1, 0, // LTE register 0 and register 1, store result in register 2
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 1);
     }
 
     #[test]
     fn test_opcode_jeq() {
-        let mut vm = Vm::new();
-        vm.program = vec![
-            1, 0, 0, 5, // LOAD 16 to register 0
-            1, 1, 0, 5, // LOAD 16 to register 1
-            9, 0, 1, 0, // EQ register 0 and register 1
-            15, 0, 0, 0, // JEQ to register 0
-            1, 0, 0, 0, // LOAD 0 to register 0, skiped
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 5, // LOAD 5 to register 0
+            1, 1, 0, 5, // LOAD 5 to register 1
+            9, 0, 1, // EQ register 0 and register 1
+            15, 0, 0, 0, // JEQ to register 0, i.e. byte 20
+            18, 0, // INC register 0, skipped
+            12, 0, 1, // LT register 0 and register 1, skipped
             1, 1, 0, 0, // LOAD 0 to register 1
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.registers[0], 5);
         assert_eq!(vm.registers[1], 0);
 
-        let mut vm = Vm::new();
-        vm.program = vec![
-            1, 0, 0, 5, // LOAD 16 to register 0
-            1, 1, 0, 6, // LOAD 16 to register 1
-            9, 0, 1, 0, // EQ register 0 and register 1
-            15, 0, 0, 0, // JEQ to register 0
-            1, 0, 0, 0, // LOAD 0 to register 0
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 5, // LOAD 5 to register 0
+            1, 1, 0, 6, // LOAD 6 to register 1
+            9, 0, 1, // EQ register 0 and register 1
+            15, 0, 0, 0, // JEQ to register 0, i.e. byte 20
+            18, 0, // INC register 0
+            12, 0, 1, // LT register 0 and register 1
             1, 1, 0, 0, // LOAD 0 to register 1
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.cmp, 0);
-        assert_eq!(vm.registers[0], 0);
+        assert_eq!(vm.registers[0], 6);
         assert_eq!(vm.registers[1], 0);
     }
 
     #[test]
-    fn test_opcode_alloc() {
+    fn test_pc_advances_by_opcode_size() {
+        for byte in 0..=u8::MAX {
+            let opcode = Opcode::from(byte);
+            // Unconditional jumps move `pc` by design and `Igl` has no size
+            if matches!(
+                opcode,
+                Opcode::Igl
+                    | Opcode::Jmp
+                    | Opcode::Jmpf
+                    | Opcode::Jmpb
+                    | Opcode::Switch
+                    | Opcode::Call
+                    | Opcode::Ret
+            ) {
+                continue;
+            }
+
+            for encoding in [Encoding::Packed, Encoding::Padded] {
+                let mut vm = Vm::new();
+                vm.set_encoding(encoding);
+                vm.registers[1] = 1;
+                vm.heap = vec![0; 2];
+                vm.stack = vec![0];
+                let mut program = vec![byte];
+                program.resize(opcode.size(), 1);
+                // Register lists pull in one more register per listed register
+                let size = instruction_size(&program, 0).unwrap();
+                program.resize(encoding.width(size), 1);
+                let len = program.len();
+                vm.set_program(program).unwrap();
+                vm.run_once().unwrap();
+
+                assert_eq!(vm.pc, len, "{:?} {:?}", opcode, encoding);
+            }
+        }
+    }
+
+    #[test]
+    fn test_opcode_move() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 9, // LOAD 9 to register 0
+            43, 1, 0, // MOVE register 0 into register 1
+            17, 0, // ALLOC register 0
+            43, 2, 0, // MOVE register 0 into register 2
+        ]);
+        vm.enable_register_tags();
+        vm.run_once().unwrap();
+        vm.run_once().unwrap();
+
+        assert_eq!(&vm.registers[..2], &[9, 9]);
+        vm.run();
+        assert_eq!(vm.registers[2], 9);
+        assert_eq!(vm.register_tags().unwrap()[2], RegTag::Address);
+    }
+
+    #[test]
+    fn test_opcode_isqrt() {
+        let mut vm = Vm::with_program(vec![
+            40, 1, 0, // ISQRT register 0, store result in register 1
+        ]);
+        let cases = [
+            (0, 0),
+            (1, 1),
+            (16, 4),
+            (17, 4),
+            (24, 4),
+            (25, 5),
+            (i32::MAX, 46340),
+        ];
+        for (value, expected) in cases {
+            vm.pc = 0;
+            vm.registers[0] = value;
+            vm.run();
+            assert_eq!(vm.registers[1], expected, "isqrt({})", value);
+        }
+
+        vm.pc = 0;
+        vm.registers[0] = -4;
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::NegativeSquareRoot { pc: 0, value: -4 })
+        );
+        assert_eq!(vm.registers[1], 46340);
+    }
+
+    #[test]
+    fn test_opcode_push_pop() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 5, // LOAD 5 to register 0
+            1, 1, 0, 9, // LOAD 9 to register 1
+            38, 0, // PUSH register 0
+            38, 1, // PUSH register 1
+            39, 0, // POP register 0
+            39, 1, // POP register 1
+            39, 2, // POP register 2
+        ]);
+
+        assert_eq!(vm.run(), VmExit::Error(VmError::StackUnderflow { pc: 16 }));
+        assert_eq!(&vm.registers[..3], &[9, 5, 0]);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_opcode_popcnt() {
+        for (value, expected) in [(0, 0), (-1, 32), (0b1011_0001, 4)] {
+            let mut vm = Vm::new();
+            vm.registers[0] = value;
+            vm.set_program(vec![
+                20, 1, 0, // POPCNT register 0, store result in register 1
+            ])
+            .unwrap();
+            vm.run();
+            assert_eq!(vm.registers[1], expected);
+        }
+    }
+
+    #[test]
+    fn test_opcode_hltif() {
+        let program = vec![
+            9, 0, 1,  // EQ register 0 and register 1
+            21, // HLTIF
+            18, 2, // INC register 2, skipped
+        ];
+        let mut vm = Vm::new();
+        assert_eq!(vm.execute(&program), VmExit::Halted);
+        assert_eq!(vm.pc, 4);
+        assert_eq!(vm.registers[2], 0);
+
+        let mut vm = Vm::new();
+        vm.registers[1] = 1;
+        vm.set_program(vec![
+            9, 0, 1,  // EQ register 0 and register 1
+            21, // HLTIF
+            18, 2, // INC register 2
+        ])
+        .unwrap();
+        vm.run();
+        assert_eq!(vm.pc, 6);
+        assert_eq!(vm.registers[2], 1);
+    }
+
+    #[test]
+    fn test_opcode_switch() {
+        let program = [
+            1, 1, 0, 7, // LOAD 7 to register 1
+            22, 0, 1, // SWITCH on register 0 with the table at register 1
+            6, 255, 0, 19, // JMP 19
+            6, 255, 0, 24, // JMP 24
+            6, 255, 0, 29, // JMP 29
+            1, 2, 0, 10, // LOAD 10 to register 2
+            0,  // HLT
+            1, 2, 0, 20, // LOAD 20 to register 2
+            0,  // HLT
+            1, 2, 0, 30, // LOAD 30 to register 2
+            0,  // HLT
+        ];
+
+        for (index, expected) in [(0, 10), (1, 20), (2, 30)] {
+            let mut vm = Vm::new();
+            vm.registers[0] = index;
+            assert_eq!(vm.execute(&program), VmExit::Halted);
+            assert_eq!(vm.registers[2], expected);
+        }
+
         let mut vm = Vm::new();
-        vm.program = vec![
+        vm.registers[0] = 10;
+        assert_eq!(
+            vm.execute(&program),
+            VmExit::Error(VmError::JumpOutOfBounds { pc: 4, target: 47 })
+        );
+    }
+
+    #[test]
+    fn test_opcode_sumall() {
+        let mut vm = Vm::with_program(vec![
+            23, 0, 3, 1, 2, 3, // SUMALL registers 1, 2 and 3, store result in register 0
+            23, 4, 0, // SUMALL of no registers, store result in register 4
+        ]);
+        vm.registers[1] = 2;
+        vm.registers[2] = 3;
+        vm.registers[3] = -10;
+        vm.registers[4] = 7;
+
+        assert_eq!(vm.run(), VmExit::EndOfProgram);
+        assert_eq!(vm.registers[0], -5);
+        assert_eq!(vm.registers[4], 0);
+    }
+
+    #[test]
+    fn test_opcode_incif() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 0, // LOAD 0 to register 0
+            1, 1, 0, 5, // LOAD 5 to register 1
+            1, 2, 0, 3, // LOAD 3 to register 2
+            1, 4, 0, 1, // LOAD 1 to register 4
+            9, 0, 2, // EQ register 0 and register 2
+            27, 3, // INCIF register 3
+            9, 0, 4, // EQ register 0 and register 4
+            27, 3, // INCIF register 3
+            18, 0, // INC register 0
+            12, 0, 1, // LT register 0 and register 1
+            15, 255, 0, 16, // JEQ 16
+            0,  // HLT
+        ]);
+
+        assert_eq!(vm.run(), VmExit::Halted);
+        assert_eq!(vm.registers[0], 5);
+        assert_eq!(vm.registers[3], 2);
+    }
+
+    #[test]
+    fn test_opcode_notcmp() {
+        let mut vm = Vm::with_program(vec![
+            9, 0, 1,  // EQ register 0 and register 1
+            28, // NOTCMP
+        ]);
+        vm.run_once().unwrap();
+        assert_eq!(vm.cmp, 1);
+
+        vm.run_once().unwrap();
+        assert_eq!(vm.cmp, 0);
+
+        vm.pc = 3;
+        vm.run_once().unwrap();
+        assert_eq!(vm.cmp, 1);
+    }
+
+    #[test]
+    fn test_opcode_clamp() {
+        for (value, expected) in [(-5, 0), (7, 7), (20, 10)] {
+            let mut vm = Vm::with_program(vec![
+                24, 0, 1, 2, // CLAMP register 0 between register 1 and register 2
+            ]);
+            vm.registers[0] = value;
+            vm.registers[2] = 10;
+
+            assert_eq!(vm.run(), VmExit::EndOfProgram);
+            assert_eq!(vm.registers[0], expected);
+        }
+    }
+
+    #[test]
+    fn test_opcode_clamp_invalid_range() {
+        let mut vm = Vm::with_program(vec![
+            24, 0, 1, 2, // CLAMP register 0 between register 1 and register 2
+        ]);
+        vm.registers[1] = 3;
+
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::InvalidRange {
+                pc: 0,
+                lo: 3,
+                hi: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_packed_matches_padded() {
+        let mut packed = Vm::with_program(vec![
+            1, 0, 0, 11, // LOAD 11 to register 0
+            1, 1, 0, 2, // LOAD 2 to register 1
+            5, 0, 1, 2, // DIV register 0 and register 1, store result in register 2
+            9, 2, 2, // EQ register 2 and register 2
+            18, 3, // INC register 3
+            20, 4, 0, // POPCNT register 0, store result in register 4
+            0, // HLT
+        ]);
+        let mut padded = Vm::with_program(vec![
+            1, 0, 0, 11, // LOAD 11 to register 0
+            1, 1, 0, 2, // LOAD 2 to register 1
+            5, 0, 1, 2, // DIV register 0 and register 1, store result in register 2
+            9, 2, 2, 0, // EQ register 2 and register 2
+            18, 3, 0, 0, // INC register 3
+            20, 4, 0, 0, // POPCNT register 0, store result in register 4
+            0, 0, 0, 0, // HLT
+        ]);
+        padded.set_encoding(Encoding::Padded);
+
+        assert_eq!(packed.run(), VmExit::Halted);
+        assert_eq!(padded.run(), VmExit::Halted);
+        assert_eq!(packed.registers, padded.registers);
+        assert_eq!((packed.rem, packed.cmp), (padded.rem, padded.cmp));
+        assert_eq!(packed.pc, 21);
+        assert_eq!(padded.pc, 28);
+    }
+
+    #[test]
+    fn test_opcode_jmpf_padded() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 1, // LOAD 1 to register 0
+            7, 0, 0, 0, // JMPF to register 0
+            1, 0, 0, 2, // LOAD 2 to register 0, skipped
+            0, 0, 0, 0, // HLT
+        ]);
+        vm.set_encoding(Encoding::Padded);
+        vm.run();
+
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(vm.pc, 16);
+    }
+
+    #[test]
+    fn test_opcode_alloc() {
+        let mut vm = Vm::with_program(vec![
             1, 0, 0, 10, // LOAD 10 to register 0
             17, 0, 0, 0,
-        ];
+        ]);
         vm.run();
         assert_eq!(vm.heap.len(), 10);
+        assert_eq!(vm.registers[0], 10);
+        assert_eq!(vm.register_tags(), None);
+
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 10, // LOAD 10 to register 0
+            1, 1, 0, 4, // LOAD 4 to register 1
+            17, 0, // ALLOC register 0
+            17, 1, // ALLOC register 1
+        ]);
+        vm.run();
+        assert_eq!(vm.heap.len(), 14);
+        assert_eq!(vm.registers[0], 10);
+        assert_eq!(vm.registers[1], 4);
+    }
+
+    #[test]
+    fn test_opcode_hgrow() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 6, // LOAD 6 to register 0
+            36, 0, // HGROW to register 0
+            1, 0, 0, 2, // LOAD 2 to register 0
+            36, 0, // HGROW to register 0
+            1, 0, 0, 4, // LOAD 4 to register 0
+            36, 0, // HGROW to register 0
+            1, 0, 255, 255, // LOAD -1 to register 0
+            36, 0, // HGROW to register 0
+        ]);
+        vm.run_once().unwrap();
+        vm.run_once().unwrap();
+        assert_eq!(vm.heap, vec![0; 6]);
+
+        vm.heap = vec![1, 2, 3, 4, 5, 6];
+        vm.run_once().unwrap();
+        vm.run_once().unwrap();
+        assert_eq!(vm.heap, vec![1, 2]);
+
+        vm.run_once().unwrap();
+        vm.run_once().unwrap();
+        assert_eq!(vm.heap, vec![1, 2, 0, 0]);
+        assert_eq!(vm.peak_heap(), 6);
+
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::AllocTooLarge { pc: 22, size: -1 })
+        );
+        assert_eq!(vm.heap.len(), 4);
+    }
+
+    #[test]
+    fn test_heap_limit() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 6, // LOAD 6 to register 0
+            17, 0, // ALLOC register 0
+            1, 1, 0, 3, // LOAD 3 to register 1
+            17, 1, // ALLOC register 1
+            1, 2, 0, 2, // LOAD 2 to register 2
+            17, 2, // ALLOC register 2
+        ]);
+        vm.set_heap_limit(Some(10));
+
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::AllocTooLarge { pc: 16, size: 2 })
+        );
+        assert_eq!(vm.peak_heap(), 9);
+        assert_eq!(vm.heap.len(), 9);
+    }
+
+    #[test]
+    fn test_alloc_negative_size() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 255, 255, // LOAD -1 to register 0
+            17, 0, // ALLOC register 0
+        ]);
+
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::AllocTooLarge { pc: 4, size: -1 })
+        );
+        assert_eq!(vm.peak_heap(), 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_alloc_out_of_memory() {
+        let mut vm = Vm::with_program_and_data(
+            vec![
+                1, 0, 0, 4, // LOAD 4 to register 0
+                17, 0, // ALLOC register 0
+            ],
+            vec![0],
+        );
+        vm.inject_fault(FaultKind::OutOfMemory);
+
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::OutOfMemory { pc: 4, size: 4 })
+        );
+        assert_eq!(vm.heap.len(), 1);
+        assert_eq!(vm.registers[0], 4);
+
+        // HGROW goes through the same reservation
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 8, // LOAD 8 to register 0
+            36, 0, // HGROW to register 0
+        ]);
+        vm.inject_fault(FaultKind::OutOfMemory);
+
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::OutOfMemory { pc: 4, size: 8 })
+        );
+        assert!(vm.heap.is_empty());
+    }
+
+    #[test]
+    fn test_opcode_loadm() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 4, // LOAD 4 to register 0
+            17, 0, // ALLOC register 0 bytes
+            1, 1, 0, 3, // LOAD 3 to register 1
+            25, 2, 1, // LOADM the byte at register 1 into register 2
+            1, 1, 0, 4, // LOAD 4 to register 1
+            25, 2, 1, // LOADM the byte at register 1 into register 2
+        ]);
+        vm.registers[2] = 9;
+
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::HeapOutOfBounds { pc: 17, address: 4 })
+        );
+        assert_eq!(vm.registers[2], 0);
+    }
+
+    #[test]
+    fn test_opcode_nop() {
+        let mut vm = Vm::with_program(vec![
+            42, 0, 0, 0, // NOP
+            18, 0, // INC register 0
+        ]);
+        vm.registers[1] = 7;
+        let registers = vm.registers;
+
+        vm.run_once().unwrap();
+        assert_eq!(vm.pc, 4);
+        assert_eq!(vm.registers, registers);
+        vm.run_once().unwrap();
+        assert_eq!(vm.registers[0], 1);
+    }
+
+    #[test]
+    fn test_opcode_store() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 4, // LOAD 4 to register 0
+            17, 0, // ALLOC register 0 bytes
+            1, 1, 0, 3, // LOAD 3 to register 1
+            1, 2, 1, 2, // LOAD 258 to register 2
+            41, 1, 2, // STORE the low byte of register 2 at the address in register 1
+            25, 3, 1, // LOADM the byte at register 1 into register 3
+            1, 1, 255, 255, // LOAD -1 to register 1
+            41, 1, 2, // STORE the low byte of register 2 at the address in register 1
+        ]);
+
+        assert_eq!(
+            vm.run(),
+            VmExit::Error(VmError::HeapOutOfBounds {
+                pc: 24,
+                address: -1
+            })
+        );
+        assert_eq!(vm.heap, vec![0, 0, 0, 2]);
+        assert_eq!(vm.registers[3], 2);
+    }
+
+    #[test]
+    fn test_run_capturing() {
+        let mut vm = Vm::with_program(vec![
+            1, 0, 0, 42, // LOAD 42 to register 0
+            26, 0, // PRINT register 0
+            1, 0, 255, 255, // LOAD -1 to register 0
+            26, 0, // PRINT register 0
+            0, // HLT
+        ]);
+
+        assert_eq!(vm.run_capturing(), (VmExit::Halted, "42\n-1\n".to_string()));
+    }
+
+    #[test]
+    fn test_register_tags() {
+        let mut vm = Vm::new();
+        vm.enable_register_tags();
+        vm.set_program(vec![
+            1, 0, 0, 10, // LOAD 10 to register 0
+            1, 1, 0, 10, // LOAD 10 to register 1
+            17, 0, // ALLOC register 0
+        ])
+        .unwrap();
+        vm.run();
+
+        let tags = vm.register_tags().unwrap();
+        assert_eq!(tags[0], RegTag::Address);
+        assert_eq!(tags[1], RegTag::Value);
+        assert_eq!(tags[2], RegTag::Unknown);
     }
 }
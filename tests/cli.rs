@@ -0,0 +1,54 @@
+use std::process::Command;
+
+fn run_vm(source: &str, name: &str, args: &[&str]) -> std::process::Output {
+    let dir = std::env::temp_dir().join("vm_test_cli");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, source).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_untitled2"))
+        .arg("run")
+        .arg(&path)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_run_from_entry_label() {
+    let source = ".data\ngreeting:\n.string \"hi\"\n.code\n\
+                  LOAD $0 1\nPRINT $0\nHLT\n\
+                  main:\nLOAD $0 42\nPRINT $0\nHLT\n";
+
+    let output = run_vm(source, "entry.asm", &["--entry", "main"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("42\n"));
+
+    let output = run_vm(source, "no_entry.asm", &[]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().starts_with("1\n"));
+}
+
+#[test]
+fn test_undefined_entry_label() {
+    let output = run_vm("HLT\n", "undefined_entry.asm", &["--entry", "main"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Undefined entry label: main\n"
+    );
+}
+
+#[test]
+fn test_runtime_error_reported() {
+    let output = run_vm("LOAD $0 1\nDIV $0 $1 $2\n", "div_by_zero.asm", &[]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Division by zero at 4! Terminating!\n"
+    );
+}